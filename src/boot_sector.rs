@@ -0,0 +1,189 @@
+use crate::error::Errno;
+use crate::fstype::FatDevice;
+use fatfs::{FatType, Read, Seek, SeekFrom};
+use rvfs::StrResult;
+
+/// The boot signature at the end of the boot sector
+pub const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// The max cluster count of FAT12 and FAT16
+pub(crate) const FAT12_MAX_CLUSTERS: u32 = 4084;
+pub(crate) const FAT16_MAX_CLUSTERS: u32 = 65524;
+
+/// Description:
+///
+/// The BIOS parameter block of a fat volume. fatfs parses it too, but it panics or
+/// returns a vague error on images that are not fat, so we check it ourselves before
+/// mounting and keep the layout information for the other parts of this crate.
+#[derive(Debug, Clone)]
+pub struct BootSector {
+    pub oem_name: [u8; 8],
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub fats: u8,
+    pub root_entries: u16,
+    pub total_sectors: u32,
+    pub media: u8,
+    pub sectors_per_fat: u32,
+    pub hidden_sectors: u32,
+    // only valid for fat32
+    pub root_dir_first_cluster: u32,
+    pub fs_info_sector: u16,
+    pub backup_boot_sector: u16,
+    pub volume_id: u32,
+    pub volume_label: [u8; 11],
+}
+
+impl BootSector {
+    /// Parse the first sector of the volume. Only the fields are decoded, use
+    /// [BootSector::validate] to check them.
+    pub fn parse(buf: &[u8; 512]) -> StrResult<Self> {
+        if buf[510..512] != BOOT_SIGNATURE {
+            return Err("Bad boot signature");
+        }
+        let u16_at = |pos: usize| u16::from_le_bytes([buf[pos], buf[pos + 1]]);
+        let u32_at =
+            |pos: usize| u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]);
+        let mut oem_name = [0u8; 8];
+        oem_name.copy_from_slice(&buf[3..11]);
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            n => n as u32,
+        };
+        let is_fat32 = u16_at(22) == 0;
+        let sectors_per_fat = if is_fat32 {
+            u32_at(36)
+        } else {
+            u16_at(22) as u32
+        };
+        // the extended boot record starts at 36 for fat12/16 and at 64 for fat32
+        let ext = if is_fat32 { 64 } else { 36 };
+        let mut volume_label = [0u8; 11];
+        volume_label.copy_from_slice(&buf[ext + 7..ext + 18]);
+        Ok(Self {
+            oem_name,
+            bytes_per_sector: u16_at(11),
+            sectors_per_cluster: buf[13],
+            reserved_sectors: u16_at(14),
+            fats: buf[16],
+            root_entries: u16_at(17),
+            total_sectors,
+            media: buf[21],
+            sectors_per_fat,
+            hidden_sectors: u32_at(28),
+            root_dir_first_cluster: if is_fat32 { u32_at(44) } else { 0 },
+            fs_info_sector: if is_fat32 { u16_at(48) } else { 0 },
+            backup_boot_sector: if is_fat32 { u16_at(50) } else { 0 },
+            volume_id: u32_at(ext + 3),
+            volume_label,
+        })
+    }
+
+    /// Read the boot sector from the device and check that the volume fits in it
+    pub fn read_from(mut fat_device: FatDevice) -> StrResult<Self> {
+        let device_size = fat_device.size();
+        if device_size < 512 {
+            return Err("Image is too small");
+        }
+        let mut buf = [0u8; 512];
+        fat_device
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| fat_device.read_exact(&mut buf))
+            .map_err(|_| Errno::EIO.as_str())?;
+        let boot_sector = Self::parse(&buf)?;
+        boot_sector.validate(device_size)?;
+        Ok(boot_sector)
+    }
+
+    /// Check the fields against the fat specification and the size of the device
+    pub fn validate(&self, device_size: u64) -> StrResult<()> {
+        if !matches!(self.bytes_per_sector, 512 | 1024 | 2048 | 4096) {
+            return Err("Unsupported sector size");
+        }
+        if !self.sectors_per_cluster.is_power_of_two() {
+            return Err("Invalid cluster size");
+        }
+        if self.reserved_sectors == 0 || self.fats == 0 {
+            return Err("Invalid reserved sectors or fat count");
+        }
+        if self.total_sectors == 0 || self.sectors_per_fat == 0 {
+            return Err("Invalid volume size");
+        }
+        match self.checked_first_data_sector() {
+            Some(sector) if sector < self.total_sectors => {}
+            _ => return Err("Invalid volume layout"),
+        }
+        if self.total_sectors as u64 * self.bytes_per_sector as u64 > device_size {
+            return Err("Image is smaller than the volume");
+        }
+        if self.fat_type() == FatType::Fat32 && self.root_dir_first_cluster < 2 {
+            return Err("Invalid root dir cluster");
+        }
+        Ok(())
+    }
+
+    pub fn cluster_size(&self) -> u32 {
+        self.bytes_per_sector as u32 * self.sectors_per_cluster as u32
+    }
+
+    /// The sectors used by the fixed root directory of fat12/16
+    pub fn root_dir_sectors(&self) -> u32 {
+        let bytes = self.root_entries as u32 * 32;
+        bytes.div_ceil(self.bytes_per_sector as u32)
+    }
+
+    pub fn first_fat_sector(&self) -> u32 {
+        self.reserved_sectors as u32
+    }
+
+    fn checked_first_root_dir_sector(&self) -> Option<u32> {
+        (self.fats as u32)
+            .checked_mul(self.sectors_per_fat)?
+            .checked_add(self.first_fat_sector())
+    }
+
+    fn checked_first_data_sector(&self) -> Option<u32> {
+        self.checked_first_root_dir_sector()?
+            .checked_add(self.root_dir_sectors())
+    }
+
+    /// The layout helpers saturate on a boot sector that [BootSector::validate] rejects
+    pub fn first_root_dir_sector(&self) -> u32 {
+        self.checked_first_root_dir_sector().unwrap_or(u32::MAX)
+    }
+
+    pub fn first_data_sector(&self) -> u32 {
+        self.checked_first_data_sector().unwrap_or(u32::MAX)
+    }
+
+    /// The number of data clusters, the first one is cluster 2
+    pub fn total_clusters(&self) -> u32 {
+        let data_sectors = self.total_sectors.saturating_sub(self.first_data_sector());
+        data_sectors / (self.sectors_per_cluster as u32).max(1)
+    }
+
+    /// The fat type is decided by the cluster count only
+    pub fn fat_type(&self) -> FatType {
+        let clusters = self.total_clusters();
+        if clusters <= FAT12_MAX_CLUSTERS {
+            FatType::Fat12
+        } else if clusters <= FAT16_MAX_CLUSTERS {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+
+    pub fn bytes_from_sector(&self, sector: u32) -> u64 {
+        sector as u64 * self.bytes_per_sector as u64
+    }
+
+    /// The byte offset of a data cluster, `None` for 0 and 1 which are not data clusters.
+    /// They can still come from a corrupted entry, so the caller reports it as an error.
+    pub fn cluster_offset(&self, cluster: u32) -> Option<u64> {
+        let index = cluster.checked_sub(2)?;
+        let sector =
+            self.first_data_sector() as u64 + index as u64 * self.sectors_per_cluster as u64;
+        Some(sector * self.bytes_per_sector as u64)
+    }
+}
@@ -0,0 +1,315 @@
+use crate::error::DeviceError;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::min;
+use rvfs::superblock::Device;
+use spin::Mutex;
+
+/// When the data written to the cache reaches the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// the dirty blocks are written when they are evicted or flushed
+    WriteBack,
+    /// every write goes to the device at once
+    WriteThrough,
+}
+
+struct CacheBlock {
+    data: Box<[u8]>,
+    dirty: bool,
+    // the tick of the last access, it is the key in the lru list
+    tick: u64,
+}
+
+struct CacheInner {
+    blocks: BTreeMap<usize, CacheBlock>,
+    // tick -> block id, the first one is the least recently used
+    lru: BTreeMap<u64, usize>,
+    tick: u64,
+}
+
+/// Description:
+///
+/// A LRU block cache between fatfs and the [Device]. fatfs reads and writes the FAT and
+/// the directory entries a few bytes at a time, the cache turns them into whole block
+/// I/Os and keeps the hot blocks in memory.
+///
+/// A cache with capacity 0 passes every read and write to the device.
+pub struct BlockCache {
+    device: Arc<dyn Device>,
+    block_size: usize,
+    capacity: usize,
+    policy: CachePolicy,
+    inner: Mutex<CacheInner>,
+}
+
+impl BlockCache {
+    pub fn new(
+        device: Arc<dyn Device>,
+        block_size: usize,
+        capacity: usize,
+        policy: CachePolicy,
+    ) -> Self {
+        assert!(block_size > 0);
+        Self {
+            device,
+            block_size,
+            capacity,
+            policy,
+            inner: Mutex::new(CacheInner {
+                blocks: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// A cache that keeps nothing
+    pub fn passthrough(device: Arc<dyn Device>) -> Self {
+        Self::new(device, 512, 0, CachePolicy::WriteThrough)
+    }
+
+    pub fn device(&self) -> &Arc<dyn Device> {
+        &self.device
+    }
+
+    pub fn size(&self) -> usize {
+        self.device.size()
+    }
+
+    pub fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, DeviceError> {
+        if self.capacity == 0 {
+            return self
+                .device
+                .read(buf, offset)
+                .map_err(|_| DeviceError::Read(offset));
+        }
+        let end = min(offset + buf.len(), self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        let mut inner = self.inner.lock();
+        let mut pos = offset;
+        while pos < end {
+            let id = pos / self.block_size;
+            let start = pos % self.block_size;
+            let len = min(self.block_size - start, end - pos);
+            let block = self.get_block(&mut inner, id, true)?;
+            buf[pos - offset..pos - offset + len].copy_from_slice(&block.data[start..start + len]);
+            pos += len;
+        }
+        Ok(end - offset)
+    }
+
+    pub fn write(&self, buf: &[u8], offset: usize) -> Result<usize, DeviceError> {
+        if self.capacity == 0 {
+            return self
+                .device
+                .write(buf, offset)
+                .map_err(|_| DeviceError::Write(offset));
+        }
+        let end = min(offset + buf.len(), self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        let mut inner = self.inner.lock();
+        let mut pos = offset;
+        while pos < end {
+            let id = pos / self.block_size;
+            let start = pos % self.block_size;
+            let len = min(self.block_size - start, end - pos);
+            let data = &buf[pos - offset..pos - offset + len];
+            // a block that is overwritten entirely does not need to be read
+            let block = self.get_block(&mut inner, id, len != self.block_size)?;
+            block.data[start..start + len].copy_from_slice(data);
+            match self.policy {
+                CachePolicy::WriteBack => block.dirty = true,
+                CachePolicy::WriteThrough => {
+                    self.device
+                        .write(data, pos)
+                        .map_err(|_| DeviceError::Write(pos))?;
+                }
+            }
+            pos += len;
+        }
+        Ok(end - offset)
+    }
+
+    /// Description:
+    ///
+    /// Read a range like the data of a file from the device, it does not go into the
+    /// cache, so a large read does not evict the metadata. The dirty blocks of the range
+    /// are written back first, the lock is not held during the read.
+    pub fn read_direct(&self, buf: &mut [u8], offset: usize) -> Result<usize, DeviceError> {
+        if self.capacity == 0 {
+            return self.read(buf, offset);
+        }
+        let end = min(offset + buf.len(), self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        self.drop_range(offset, end, true, false)?;
+        self.device_read(&mut buf[..end - offset], offset)?;
+        Ok(end - offset)
+    }
+
+    /// Write a range to the device past the cache, the cached blocks of the range are
+    /// written back and dropped, so no old data is read or written later.
+    pub fn write_direct(&self, buf: &[u8], offset: usize) -> Result<usize, DeviceError> {
+        if self.capacity == 0 {
+            return self.write(buf, offset);
+        }
+        let end = min(offset + buf.len(), self.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        self.drop_range(offset, end, true, true)?;
+        self.device_write(&buf[..end - offset], offset)?;
+        // a block may have been read while the lock was not held
+        self.drop_range(offset, end, false, true)?;
+        Ok(end - offset)
+    }
+
+    /// Write all dirty blocks back and flush the device
+    pub fn flush(&self) -> Result<(), DeviceError> {
+        let mut inner = self.inner.lock();
+        for (id, block) in inner.blocks.iter_mut() {
+            if block.dirty {
+                self.write_block(*id, &block.data)?;
+                block.dirty = false;
+            }
+        }
+        drop(inner);
+        self.device.flush();
+        Ok(())
+    }
+
+    /// Drop every cached block without writing it back
+    pub fn invalidate(&self) {
+        let mut inner = self.inner.lock();
+        inner.blocks.clear();
+        inner.lru.clear();
+    }
+
+    fn get_block<'a>(
+        &self,
+        inner: &'a mut CacheInner,
+        id: usize,
+        load: bool,
+    ) -> Result<&'a mut CacheBlock, DeviceError> {
+        inner.tick += 1;
+        let tick = inner.tick;
+        if inner.blocks.contains_key(&id) {
+            let block = inner.blocks.get_mut(&id).unwrap();
+            inner.lru.remove(&block.tick);
+            block.tick = tick;
+            inner.lru.insert(tick, id);
+            return Ok(block);
+        }
+        if inner.blocks.len() >= self.capacity {
+            self.evict(inner)?;
+        }
+        let mut data = vec![0u8; self.block_size].into_boxed_slice();
+        let offset = id * self.block_size;
+        // the last block may be cut by the end of the device
+        let len = min(self.block_size, self.size() - offset);
+        if load {
+            self.device_read(&mut data[..len], offset)?;
+        }
+        inner.lru.insert(tick, id);
+        inner.blocks.insert(
+            id,
+            CacheBlock {
+                data,
+                dirty: false,
+                tick,
+            },
+        );
+        Ok(inner.blocks.get_mut(&id).unwrap())
+    }
+
+    // Write back the dirty blocks of the bytes from `start` to `end` if `write_back`, and
+    // remove the clean ones if `remove`. A dirty block that is not written stays.
+    fn drop_range(
+        &self,
+        start: usize,
+        end: usize,
+        write_back: bool,
+        remove: bool,
+    ) -> Result<(), DeviceError> {
+        let mut inner = self.inner.lock();
+        let ids = inner
+            .blocks
+            .range(start / self.block_size..=(end - 1) / self.block_size)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            let block = inner.blocks.get_mut(&id).unwrap();
+            if block.dirty && write_back {
+                self.write_block(id, &block.data)?;
+                block.dirty = false;
+            }
+            if !block.dirty && remove {
+                let tick = block.tick;
+                inner.blocks.remove(&id);
+                inner.lru.remove(&tick);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the least recently used block, it is written back if it is dirty
+    fn evict(&self, inner: &mut CacheInner) -> Result<(), DeviceError> {
+        if let Some((tick, id)) = inner.lru.pop_first() {
+            let block = inner.blocks.remove(&id).unwrap();
+            if block.dirty
+                && let Err(err) = self.write_block(id, &block.data)
+            {
+                // keep the data, it may be written later
+                inner.lru.insert(tick, id);
+                inner.blocks.insert(id, block);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, id: usize, data: &[u8]) -> Result<(), DeviceError> {
+        let offset = id * self.block_size;
+        let len = min(self.block_size, self.size() - offset);
+        self.device_write(&data[..len], offset)
+    }
+
+    fn device_read(&self, buf: &mut [u8], offset: usize) -> Result<(), DeviceError> {
+        let mut read = 0;
+        while read < buf.len() {
+            let n = self
+                .device
+                .read(&mut buf[read..], offset + read)
+                .map_err(|_| DeviceError::Read(offset + read))?;
+            if n == 0 {
+                return Err(DeviceError::UnexpectedEof);
+            }
+            read += n;
+        }
+        Ok(())
+    }
+
+    fn device_write(&self, buf: &[u8], offset: usize) -> Result<(), DeviceError> {
+        let mut written = 0;
+        while written < buf.len() {
+            let n = self
+                .device
+                .write(&buf[written..], offset + written)
+                .map_err(|_| DeviceError::Write(offset + written))?;
+            if n == 0 {
+                return Err(DeviceError::WriteZero);
+            }
+            written += n;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,970 @@
+use crate::boot_sector::BootSector;
+use crate::codepage::CodePage;
+use crate::error::{FatError, FatResult};
+use crate::extent::ExtentMap;
+use crate::fstype::FatDevice;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::min;
+use fatfs::{DateTime, FatType, OemCpConverter};
+
+/// The size of a directory entry
+pub const DIR_ENTRY_SIZE: u64 = 32;
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_LFN: u8 = 0x0F;
+/// The first byte of a deleted entry
+pub const DELETED_ENTRY: u8 = 0xE5;
+/// The flag of the last (first on disk) long name entry
+pub const LFN_LAST: u8 = 0x40;
+/// The flags of a lower case base name and extension, they are set by Windows NT
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+/// The characters of a short name besides the letters, the digits and the code page
+const SHORT_NAME_CHARS: &[u8] = b"!#$%&'()-@^_`{}~";
+/// The positions of the 13 UTF-16 units in a long name entry
+const LFN_CHAR_POS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// The largest file, the size of an entry has 32 bits
+pub const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+/// The inode number of the root directory
+pub const ROOT_INO: usize = 1;
+
+/// A date and a time as they are stored in a directory entry, the time has a resolution
+/// of 2 seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DosDateTime {
+    pub date: u16,
+    pub time: u16,
+}
+
+impl From<DateTime> for DosDateTime {
+    fn from(value: DateTime) -> Self {
+        let DateTime { date, time } = value;
+        Self {
+            date: (date.year.saturating_sub(1980) << 9) | (date.month << 5) | date.day,
+            time: (time.hour << 11) | (time.min << 5) | (time.sec / 2),
+        }
+    }
+}
+
+/// Description:
+///
+/// A directory entry as it is stored on disk. A long name entry uses the same 32 bytes
+/// with another layout, see the `lfn_*` methods.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RawDirEntry(pub [u8; 32]);
+
+impl RawDirEntry {
+    fn u16_at(&self, pos: usize) -> u16 {
+        u16::from_le_bytes([self.0[pos], self.0[pos + 1]])
+    }
+    fn set_u16_at(&mut self, pos: usize, value: u16) {
+        self.0[pos..pos + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// The short name, 8 + 3 bytes padded with spaces
+    pub fn name(&self) -> &[u8; 11] {
+        self.0[0..11].try_into().unwrap()
+    }
+    pub fn attr(&self) -> u8 {
+        self.0[11]
+    }
+    /// The entry and all entries after it are free
+    pub fn is_end(&self) -> bool {
+        self.0[0] == 0
+    }
+    pub fn is_deleted(&self) -> bool {
+        self.0[0] == DELETED_ENTRY
+    }
+    pub fn is_lfn(&self) -> bool {
+        self.attr() & 0x3F == ATTR_LFN
+    }
+    pub fn is_volume_label(&self) -> bool {
+        !self.is_lfn() && self.attr() & ATTR_VOLUME_ID != 0
+    }
+    pub fn is_dir(&self) -> bool {
+        !self.is_lfn() && self.attr() & ATTR_DIRECTORY != 0
+    }
+    /// The entry is `.` or `..`
+    pub fn is_dot(&self) -> bool {
+        self.name() == b".          " || self.name() == b"..         "
+    }
+    pub fn first_cluster(&self) -> u32 {
+        (self.u16_at(20) as u32) << 16 | self.u16_at(26) as u32
+    }
+    pub fn set_first_cluster(&mut self, cluster: u32) {
+        self.set_u16_at(20, (cluster >> 16) as u16);
+        self.set_u16_at(26, cluster as u16);
+    }
+    pub fn size(&self) -> u32 {
+        u32::from_le_bytes(self.0[28..32].try_into().unwrap())
+    }
+    pub fn set_size(&mut self, size: u32) {
+        self.0[28..32].copy_from_slice(&size.to_le_bytes());
+    }
+    /// The time of the last write
+    pub fn modified(&self) -> DosDateTime {
+        DosDateTime {
+            date: self.u16_at(24),
+            time: self.u16_at(22),
+        }
+    }
+    pub fn set_modified(&mut self, modified: DosDateTime) {
+        self.set_u16_at(22, modified.time);
+        self.set_u16_at(24, modified.date);
+    }
+    /// The time of the creation and its 10 ms units over the 2 seconds
+    pub fn created(&self) -> (DosDateTime, u8) {
+        let created = DosDateTime {
+            date: self.u16_at(16),
+            time: self.u16_at(14),
+        };
+        (created, self.0[13])
+    }
+    pub fn set_created(&mut self, created: DosDateTime, centis: u8) {
+        self.0[13] = centis;
+        self.set_u16_at(14, created.time);
+        self.set_u16_at(16, created.date);
+    }
+    /// The day of the last access, FAT does not keep its time
+    pub fn accessed(&self) -> DosDateTime {
+        DosDateTime {
+            date: self.u16_at(18),
+            time: 0,
+        }
+    }
+    pub fn set_accessed(&mut self, accessed: DosDateTime) {
+        self.set_u16_at(18, accessed.date);
+    }
+    pub fn delete(&mut self) {
+        self.0[0] = DELETED_ENTRY;
+    }
+
+    /// The 8.3 name without the padding, the lower case flags are applied
+    pub fn short_name(&self, codepage: &CodePage) -> String {
+        let mut name = *self.name();
+        // 0x05 stands for a first byte 0xE5
+        if name[0] == 0x05 {
+            name[0] = DELETED_ENTRY;
+        }
+        let case = self.0[12];
+        let part = |bytes: &[u8], lower: bool| {
+            let len = bytes.iter().rposition(|x| *x != b' ').map_or(0, |x| x + 1);
+            let name = codepage.decode_str(&bytes[..len]);
+            if lower {
+                name.chars().flat_map(char::to_lowercase).collect()
+            } else {
+                name
+            }
+        };
+        let mut res = part(&name[..8], case & CASE_LOWER_BASE != 0);
+        let ext = part(&name[8..], case & CASE_LOWER_EXT != 0);
+        if !ext.is_empty() {
+            res.push('.');
+            res.push_str(&ext);
+        }
+        res
+    }
+
+    /// The short name like fatfs gives it: `BASE.EXT` without the padding, with 0x05
+    /// read as 0xE5
+    pub fn fatfs_short_name(&self) -> Vec<u8> {
+        let mut name = *self.name();
+        // 0x05 stands for a first byte 0xE5
+        if name[0] == 0x05 {
+            name[0] = DELETED_ENTRY;
+        }
+        let part = |bytes: &[u8]| {
+            let len = bytes.iter().rposition(|x| *x != b' ').map_or(0, |x| x + 1);
+            bytes[..len].to_vec()
+        };
+        let mut res = part(&name[..8]);
+        let ext = part(&name[8..]);
+        if !ext.is_empty() {
+            res.push(b'.');
+            res.extend_from_slice(&ext);
+        }
+        res
+    }
+
+    /// The checksum of the short name, it is saved in every long name entry of the file
+    pub fn short_name_checksum(&self) -> u8 {
+        self.name()
+            .iter()
+            .fold(0u8, |sum, x| sum.rotate_right(1).wrapping_add(*x))
+    }
+    /// The order of a long name entry without the last flag, it starts from 1
+    pub fn lfn_order(&self) -> u8 {
+        self.0[0] & !LFN_LAST
+    }
+    pub fn lfn_is_last(&self) -> bool {
+        self.0[0] & LFN_LAST != 0
+    }
+    pub fn lfn_checksum(&self) -> u8 {
+        self.0[13]
+    }
+    pub fn set_lfn_checksum(&mut self, checksum: u8) {
+        self.0[13] = checksum;
+    }
+    /// The 13 UTF-16 units of a long name entry
+    pub fn lfn_chars(&self) -> [u16; 13] {
+        LFN_CHAR_POS.map(|pos| self.u16_at(pos))
+    }
+    pub fn set_lfn_chars(&mut self, chars: &[u16; 13]) {
+        for (c, pos) in chars.iter().zip(LFN_CHAR_POS) {
+            self.set_u16_at(pos, *c);
+        }
+    }
+}
+
+impl core::fmt::Debug for RawDirEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("RawDirEntry")
+            .field("name", &core::str::from_utf8(self.name()))
+            .field("attr", &self.attr())
+            .field("first_cluster", &self.first_cluster())
+            .field("size", &self.size())
+            .finish()
+    }
+}
+
+/// The long name entries read so far, they are stored from the last part to the first
+#[derive(Default)]
+pub(crate) struct LfnBuilder {
+    pub(crate) offsets: Vec<u64>,
+    chars: Vec<u16>,
+    checksum: u8,
+    // the order of the last entry
+    order: u8,
+}
+
+impl LfnBuilder {
+    pub(crate) fn start(&mut self, offset: u64, entry: &RawDirEntry) {
+        self.offsets.push(offset);
+        self.chars = entry.lfn_chars().to_vec();
+        self.checksum = entry.lfn_checksum();
+        self.order = entry.lfn_order();
+    }
+
+    pub(crate) fn accepts(&self, entry: &RawDirEntry) -> bool {
+        !self.offsets.is_empty()
+            && entry.lfn_order() + 1 == self.order
+            && entry.lfn_checksum() == self.checksum
+    }
+
+    pub(crate) fn push(&mut self, offset: u64, entry: &RawDirEntry) {
+        if !self.accepts(entry) {
+            // a part without its start, it is dropped with the next entry
+            self.offsets.push(offset);
+            self.order = 0;
+            return;
+        }
+        self.offsets.push(offset);
+        let mut chars = entry.lfn_chars().to_vec();
+        chars.append(&mut self.chars);
+        self.chars = chars;
+        self.order = entry.lfn_order();
+    }
+
+    /// The long name of the short entry, none if the long name does not belong to it
+    pub(crate) fn name_for(&self, entry: &RawDirEntry) -> Option<String> {
+        if self.offsets.is_empty()
+            || self.order != 1
+            || self.checksum != entry.short_name_checksum()
+        {
+            return None;
+        }
+        let chars = self
+            .chars
+            .iter()
+            .copied()
+            .take_while(|x| *x != 0 && *x != 0xFFFF);
+        Some(
+            char::decode_utf16(chars)
+                .map(|x| x.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        )
+    }
+}
+
+/// A file in a directory: the short entry with its long name
+#[derive(Debug, Clone)]
+pub struct DirItem {
+    /// the long name, or the short name if there is none
+    pub name: String,
+    pub entry: RawDirEntry,
+    /// the position of the short entry
+    pub offset: u64,
+    /// the position of the first long name entry, it is `offset` without a long name
+    pub first_offset: u64,
+    /// the positions of the long name entries in the order on disk
+    pub lfn_offsets: Vec<u64>,
+}
+
+impl DirItem {
+    /// The long name or the short name is `name`, both ignore the case
+    pub fn matches(&self, name: &str, codepage: &CodePage) -> bool {
+        name_eq(&self.name, name) || name_eq(&self.entry.short_name(codepage), name)
+    }
+
+    /// Description:
+    ///
+    /// The name that fatfs finds the file by: the long name, or the short name that
+    /// fatfs decodes one byte at a time. It is not the name of the vfs for a short name
+    /// in a double byte code page, see the `OemCpConverter` of [CodePage].
+    pub fn fatfs_name(&self, codepage: &CodePage) -> String {
+        if !self.lfn_offsets.is_empty() {
+            return self.name.clone();
+        }
+        self.entry
+            .fatfs_short_name()
+            .iter()
+            .map(|x| OemCpConverter::decode(codepage, *x))
+            .collect()
+    }
+}
+
+/// Turns the entries of a directory into [DirItem]s, one entry at a time
+struct ItemBuilder {
+    lfn: LfnBuilder,
+    codepage: CodePage,
+}
+
+impl ItemBuilder {
+    fn new(codepage: CodePage) -> Self {
+        Self {
+            lfn: LfnBuilder::default(),
+            codepage,
+        }
+    }
+    /// Take the next entry, a short entry that is a file gives its item
+    fn push(&mut self, offset: u64, entry: RawDirEntry) -> Option<DirItem> {
+        let lfn = &mut self.lfn;
+        if entry.is_deleted() {
+            *lfn = LfnBuilder::default();
+            return None;
+        }
+        if entry.is_lfn() {
+            if entry.lfn_is_last() || !lfn.accepts(&entry) {
+                *lfn = LfnBuilder::default();
+            }
+            if entry.lfn_is_last() {
+                lfn.start(offset, &entry);
+            } else {
+                lfn.push(offset, &entry);
+            }
+            return None;
+        }
+        let (name, lfn_offsets) = match lfn.name_for(&entry) {
+            Some(name) => (name, core::mem::take(&mut lfn.offsets)),
+            None => (entry.short_name(&self.codepage), Vec::new()),
+        };
+        let first_offset = lfn_offsets.first().copied().unwrap_or(offset);
+        *lfn = LfnBuilder::default();
+        if entry.is_volume_label() {
+            return None;
+        }
+        Some(DirItem {
+            name,
+            entry,
+            offset,
+            first_offset,
+            lfn_offsets,
+        })
+    }
+}
+
+/// The name that vfat saves and looks up, the dots at the end are dropped
+pub fn trim_name(name: &str) -> &str {
+    match name {
+        "." | ".." => name,
+        _ => name.trim_end_matches('.'),
+    }
+}
+
+/// Description:
+///
+/// A name in upper case one character at a time, like the `toupper` of the nls tables of
+/// vfat. A character whose upper case is more than one character, like `ß`, stays as it
+/// is, so a name keeps its length. The lookup, the dentry cache and the short names all
+/// fold the names with it.
+pub fn fold_name(name: &str) -> impl Iterator<Item = char> + '_ {
+    name.chars().map(|c| {
+        let mut upper = c.to_uppercase();
+        match (upper.next(), upper.next()) {
+            (Some(upper), None) => upper,
+            _ => c,
+        }
+    })
+}
+
+/// Compare two names without the case, see [fold_name]
+pub fn name_eq(a: &str, b: &str) -> bool {
+    fold_name(a).eq(fold_name(b))
+}
+
+/// Description:
+///
+/// The 8.3 name of `name` in `codepage` like vfat makes it: [fold_name], without the spaces
+/// and the dots, `_` for a character the code page does not have. A name that does not fit,
+/// loses a character or is `taken` gets the first free `~n` tail. None if all are taken.
+fn oem_short_name(name: &str, codepage: &CodePage, taken: &[[u8; 11]]) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rfind('.').filter(|x| *x > 0) {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    let mut lossy = false;
+    // the bytes and where their characters start, a cut must not split two bytes
+    let mut encode = |part: &str| {
+        let mut bytes = Vec::new();
+        let mut starts = Vec::new();
+        for (c, upper) in part.chars().zip(fold_name(part)) {
+            if c == ' ' || c == '.' {
+                lossy = true;
+                continue;
+            }
+            starts.push(bytes.len());
+            let ok = match upper {
+                x if x.is_ascii_alphanumeric() => {
+                    bytes.push(x as u8);
+                    true
+                }
+                x if x.is_ascii() && SHORT_NAME_CHARS.contains(&(x as u8)) => {
+                    bytes.push(x as u8);
+                    true
+                }
+                x if x.is_ascii() => false,
+                // some code pages only have the lower case, like ÿ in CP437
+                x => codepage.encode_to(x, &mut bytes) || codepage.encode_to(c, &mut bytes),
+            };
+            if !ok {
+                lossy = true;
+                bytes.push(b'_');
+            }
+        }
+        starts.push(bytes.len());
+        (bytes, starts)
+    };
+    // the length of the bytes of the whole characters that fit in `max`
+    let fit = |starts: &[usize], max: usize| starts.iter().rev().find(|x| **x <= max).copied();
+    let (base, base_starts) = encode(base);
+    let (ext, ext_starts) = encode(ext);
+    let mut short = [b' '; 11];
+    let ext_len = fit(&ext_starts, 3).unwrap_or(0);
+    short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+    // 0x05 stands for a first byte 0xE5
+    let escape = |mut name: [u8; 11]| {
+        if name[0] == DELETED_ENTRY {
+            name[0] = 0x05;
+        }
+        name
+    };
+    if !lossy && !base.is_empty() && base.len() <= 8 && ext.len() <= 3 {
+        let mut name = short;
+        name[..base.len()].copy_from_slice(&base);
+        let name = escape(name);
+        if !taken.contains(&name) {
+            return Some(name);
+        }
+    }
+    (1..1_000_000).find_map(|n| {
+        let tail = format!("~{}", n);
+        let len = fit(&base_starts, 8 - tail.len()).unwrap_or(0);
+        let mut name = short;
+        name[..len].copy_from_slice(&base[..len]);
+        name[len..len + tail.len()].copy_from_slice(tail.as_bytes());
+        Some(escape(name)).filter(|x| !taken.contains(x))
+    })
+}
+
+/// Description:
+///
+/// Direct access to the FAT and the directory entries of a volume. fatfs keeps them
+/// private, but the checker and the inode numbers need the on-disk locations.
+///
+/// The device may share its [crate::cache::BlockCache] with fatfs, then both of them
+/// see the same data.
+#[derive(Clone)]
+pub struct Volume {
+    device: FatDevice,
+    boot_sector: BootSector,
+    // the code page of the short names
+    codepage: CodePage,
+}
+
+impl Volume {
+    pub fn new(device: FatDevice) -> FatResult<Self> {
+        let boot_sector = BootSector::read_from(device.clone())
+            .map_err(|_| FatError::from(fatfs::Error::CorruptedFileSystem))?;
+        Self::with_boot_sector(device, boot_sector)
+    }
+
+    pub fn with_boot_sector(device: FatDevice, boot_sector: BootSector) -> FatResult<Self> {
+        let volume = Self {
+            device,
+            boot_sector,
+            codepage: CodePage::default(),
+        };
+        // the FAT must have an entry for every cluster
+        if volume.fat_bytes() > volume.sectors_bytes(volume.boot_sector.sectors_per_fat) {
+            return Err(fatfs::Error::CorruptedFileSystem.into());
+        }
+        Ok(volume)
+    }
+
+    /// Decode the short names with `codepage`, it is CP437 by default
+    pub fn with_codepage(mut self, codepage: CodePage) -> Self {
+        self.codepage = codepage;
+        self
+    }
+
+    pub fn codepage(&self) -> CodePage {
+        self.codepage
+    }
+
+    pub fn boot_sector(&self) -> &BootSector {
+        &self.boot_sector
+    }
+
+    pub fn device(&self) -> FatDevice {
+        self.device.clone()
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.boot_sector.fat_type()
+    }
+
+    pub fn cluster_size(&self) -> u32 {
+        self.boot_sector.cluster_size()
+    }
+
+    /// The number of the last data cluster
+    pub fn max_cluster(&self) -> u32 {
+        self.boot_sector.total_clusters() + 1
+    }
+
+    /// The first cluster of the root directory, 0 for the fixed root of fat12/16
+    pub fn root_cluster(&self) -> u32 {
+        match self.fat_type() {
+            FatType::Fat32 => self.boot_sector.root_dir_first_cluster,
+            _ => 0,
+        }
+    }
+
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> FatResult<()> {
+        self.device().read_at(offset, buf)?;
+        Ok(())
+    }
+
+    pub fn write_at(&self, offset: u64, buf: &[u8]) -> FatResult<()> {
+        self.device().write_at(offset, buf)?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> FatResult<()> {
+        self.device.cache().flush()?;
+        Ok(())
+    }
+
+    fn sectors_bytes(&self, sectors: u32) -> u64 {
+        self.boot_sector.bytes_from_sector(sectors)
+    }
+
+    // the bytes of the FAT entries in use
+    fn fat_bytes(&self) -> u64 {
+        let entries = self.max_cluster() as u64 + 1;
+        match self.fat_type() {
+            FatType::Fat12 => (entries * 3).div_ceil(2),
+            FatType::Fat16 => entries * 2,
+            FatType::Fat32 => entries * 4,
+        }
+    }
+
+    /// The byte offset of a copy of the FAT
+    pub fn fat_offset(&self, fat: u8) -> u64 {
+        let bs = &self.boot_sector;
+        self.sectors_bytes(bs.first_fat_sector() + fat as u32 * bs.sectors_per_fat)
+    }
+
+    /// The raw bytes of a copy of the FAT
+    pub fn read_fat_bytes(&self, fat: u8) -> FatResult<Vec<u8>> {
+        let mut buf = vec![0u8; self.sectors_bytes(self.boot_sector.sectors_per_fat) as usize];
+        self.read_at(self.fat_offset(fat), &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Decode every entry of a copy of the FAT, the index is the cluster number
+    pub fn read_fat(&self, fat: u8) -> FatResult<Vec<u32>> {
+        let bytes = self.read_fat_bytes(fat)?;
+        let entries = (0..=self.max_cluster())
+            .map(|cluster| self.decode_entry(&bytes, cluster))
+            .collect();
+        Ok(entries)
+    }
+
+    fn decode_entry(&self, bytes: &[u8], cluster: u32) -> u32 {
+        let cluster = cluster as usize;
+        match self.fat_type() {
+            FatType::Fat12 => {
+                let pos = cluster * 3 / 2;
+                let value = u16::from_le_bytes([bytes[pos], bytes[pos + 1]]);
+                if cluster & 1 == 1 {
+                    (value >> 4) as u32
+                } else {
+                    (value & 0xFFF) as u32
+                }
+            }
+            FatType::Fat16 => {
+                u16::from_le_bytes([bytes[cluster * 2], bytes[cluster * 2 + 1]]) as u32
+            }
+            FatType::Fat32 => {
+                let pos = cluster * 4;
+                u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) & 0x0FFF_FFFF
+            }
+        }
+    }
+
+    // the position and the length of the entry in a FAT
+    fn entry_pos(&self, cluster: u32) -> (u64, usize) {
+        let cluster = cluster as u64;
+        match self.fat_type() {
+            FatType::Fat12 => (cluster * 3 / 2, 2),
+            FatType::Fat16 => (cluster * 2, 2),
+            FatType::Fat32 => (cluster * 4, 4),
+        }
+    }
+
+    /// Read the entry of a cluster from the first FAT
+    pub fn fat_entry(&self, cluster: u32) -> FatResult<u32> {
+        if cluster > self.max_cluster() {
+            return Err(fatfs::Error::CorruptedFileSystem.into());
+        }
+        let (pos, len) = self.entry_pos(cluster);
+        let mut buf = [0u8; 4];
+        self.read_at(self.fat_offset(0) + pos, &mut buf[..len])?;
+        let value = u32::from_le_bytes(buf);
+        Ok(match self.fat_type() {
+            FatType::Fat12 if cluster & 1 == 1 => (value >> 4) & 0xFFF,
+            FatType::Fat12 => value & 0xFFF,
+            FatType::Fat16 => value,
+            FatType::Fat32 => value & 0x0FFF_FFFF,
+        })
+    }
+
+    /// Set the entry of a cluster in every FAT
+    pub fn set_fat_entry(&self, cluster: u32, value: u32) -> FatResult<()> {
+        if cluster > self.max_cluster() {
+            return Err(fatfs::Error::InvalidInput.into());
+        }
+        let (pos, len) = self.entry_pos(cluster);
+        for fat in 0..self.boot_sector.fats {
+            let offset = self.fat_offset(fat) + pos;
+            let mut buf = [0u8; 4];
+            self.read_at(offset, &mut buf[..len])?;
+            let old = u32::from_le_bytes(buf);
+            let new = match self.fat_type() {
+                FatType::Fat12 if cluster & 1 == 1 => (old & 0x000F) | (value & 0xFFF) << 4,
+                FatType::Fat12 => (old & 0xF000) | (value & 0xFFF),
+                FatType::Fat16 => value & 0xFFFF,
+                // the high 4 bits are reserved
+                FatType::Fat32 => (old & 0xF000_0000) | (value & 0x0FFF_FFFF),
+            };
+            self.write_at(offset, &new.to_le_bytes()[..len])?;
+        }
+        Ok(())
+    }
+
+    /// The end of chain mark written by this crate
+    pub fn end_of_chain(&self) -> u32 {
+        match self.fat_type() {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    /// The entry ends a cluster chain
+    pub fn is_end_of_chain(&self, value: u32) -> bool {
+        match self.fat_type() {
+            FatType::Fat12 => value >= 0xFF8,
+            FatType::Fat16 => value >= 0xFFF8,
+            FatType::Fat32 => value >= 0x0FFF_FFF8,
+        }
+    }
+
+    /// The entry marks a bad cluster
+    pub fn is_bad_cluster(&self, value: u32) -> bool {
+        match self.fat_type() {
+            FatType::Fat12 => value == 0xFF7,
+            FatType::Fat16 => value == 0xFFF7,
+            FatType::Fat32 => value == 0x0FFF_FFF7,
+        }
+    }
+
+    /// The entry points to a data cluster
+    pub fn is_data_cluster(&self, value: u32) -> bool {
+        (2..=self.max_cluster()).contains(&value)
+    }
+
+    /// Follow the cluster chain from the first cluster. A chain that points to a free or
+    /// a bad cluster, or a chain with a loop is an error.
+    pub fn chain(&self, first_cluster: u32) -> FatResult<Vec<u32>> {
+        let mut clusters = Vec::new();
+        let mut cluster = first_cluster;
+        loop {
+            if !self.is_data_cluster(cluster) || clusters.len() as u32 >= self.max_cluster() {
+                return Err(fatfs::Error::CorruptedFileSystem.into());
+            }
+            clusters.push(cluster);
+            let next = self.fat_entry(cluster)?;
+            if self.is_end_of_chain(next) {
+                return Ok(clusters);
+            }
+            cluster = next;
+        }
+    }
+
+    /// The byte offset of a data cluster, a reserved cluster means a corrupted chain
+    pub fn cluster_offset(&self, cluster: u32) -> FatResult<u64> {
+        self.boot_sector
+            .cluster_offset(cluster)
+            .ok_or(FatError::Fs(fatfs::Error::CorruptedFileSystem))
+    }
+
+    /// Description:
+    ///
+    /// Read the data of a file at `offset`. There is no cursor, the clusters are found in
+    /// the [ExtentMap] of the file, so many reads of one file can run at the same time.
+    /// The read stops at `size` or at the end of the chain, the number of bytes read is
+    /// returned.
+    pub fn read_file(
+        &self,
+        map: &ExtentMap,
+        size: u32,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> FatResult<usize> {
+        let size = size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = min(buf.len() as u64, size - offset) as usize;
+        // every run of clusters is one read of the device, the data is not cached
+        map.for_each_range(self, offset, len, |pos, start, n| {
+            self.device
+                .read_direct_at(pos, &mut buf[start..start + n])?;
+            Ok(())
+        })
+    }
+
+    /// Write the data of a file at `offset` in the clusters it already has, the bytes
+    /// after the chain are not written. The number of bytes written is returned.
+    pub fn write_file(&self, map: &ExtentMap, offset: u64, buf: &[u8]) -> FatResult<usize> {
+        map.for_each_range(self, offset, buf.len(), |pos, start, n| {
+            self.device.write_direct_at(pos, &buf[start..start + n])?;
+            Ok(())
+        })
+    }
+
+    /// The byte ranges of a directory, `first_cluster` 0 is the root directory
+    pub fn dir_regions(&self, first_cluster: u32) -> FatResult<Vec<(u64, u64)>> {
+        let bs = &self.boot_sector;
+        if first_cluster == 0 && self.fat_type() != FatType::Fat32 {
+            let offset = self.sectors_bytes(bs.first_root_dir_sector());
+            return Ok(vec![(offset, self.sectors_bytes(bs.root_dir_sectors()))]);
+        }
+        let first_cluster = if first_cluster == 0 {
+            self.root_cluster()
+        } else {
+            first_cluster
+        };
+        let cluster_size = self.cluster_size() as u64;
+        let regions = self
+            .chain(first_cluster)?
+            .into_iter()
+            .map(|cluster| Ok((self.cluster_offset(cluster)?, cluster_size)))
+            .collect::<FatResult<_>>()?;
+        Ok(regions)
+    }
+
+    /// Read the entries of the regions until the end mark, every entry comes with its
+    /// byte offset
+    pub fn read_entries(&self, regions: &[(u64, u64)]) -> FatResult<Vec<(u64, RawDirEntry)>> {
+        let mut entries = Vec::new();
+        for (offset, len) in regions {
+            let mut buf = vec![0u8; *len as usize];
+            self.read_at(*offset, &mut buf)?;
+            for (i, bytes) in buf.chunks_exact(DIR_ENTRY_SIZE as usize).enumerate() {
+                let entry = RawDirEntry(bytes.try_into().unwrap());
+                if entry.is_end() {
+                    return Ok(entries);
+                }
+                entries.push((offset + i as u64 * DIR_ENTRY_SIZE, entry));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Read the entries of a directory, `first_cluster` 0 is the root directory
+    pub fn read_dir(&self, first_cluster: u32) -> FatResult<Vec<(u64, RawDirEntry)>> {
+        self.read_entries(&self.dir_regions(first_cluster)?)
+    }
+
+    pub fn read_dir_entry(&self, offset: u64) -> FatResult<RawDirEntry> {
+        let mut entry = RawDirEntry([0u8; 32]);
+        self.read_at(offset, &mut entry.0)?;
+        Ok(entry)
+    }
+
+    pub fn write_dir_entry(&self, offset: u64, entry: &RawDirEntry) -> FatResult<()> {
+        self.write_at(offset, &entry.0)
+    }
+
+    /// Description:
+    ///
+    /// Write `name` to the long name entries of a file in place, the short entry and the
+    /// checksum stay as they are. It is for a new case of the same name. False if the
+    /// file has no long name or the new name needs another number of entries.
+    pub fn set_long_name(&self, item: &DirItem, name: &str) -> FatResult<bool> {
+        let mut units = name.encode_utf16().collect::<Vec<u16>>();
+        let count = units.len().div_ceil(13);
+        if item.lfn_offsets.is_empty() || item.lfn_offsets.len() != count {
+            return Ok(false);
+        }
+        // the name ends with a 0 and is padded with 0xFFFF, unless it fills the entries
+        if units.len() % 13 != 0 {
+            units.push(0);
+            units.resize(count * 13, 0xFFFF);
+        }
+        // the first entry on disk holds the end of the name
+        for (part, offset) in units.chunks_exact(13).rev().zip(&item.lfn_offsets) {
+            let mut entry = self.read_dir_entry(*offset)?;
+            entry.set_lfn_chars(part.try_into().unwrap());
+            self.write_dir_entry(*offset, &entry)?;
+        }
+        Ok(true)
+    }
+
+    /// Description:
+    ///
+    /// Give a file that fatfs has just named the short name of [oem_short_name]. fatfs
+    /// writes `_` for every character outside ASCII, the code page of the volume keeps
+    /// them. The long name entries get the checksum of the new short name. False if the
+    /// name is ASCII or the short name stays as it is.
+    pub fn set_oem_short_name(&self, dir_cluster: u32, item: &DirItem) -> FatResult<bool> {
+        if item.name.is_ascii() || item.lfn_offsets.is_empty() {
+            return Ok(false);
+        }
+        let taken = self
+            .read_dir_items(dir_cluster)?
+            .into_iter()
+            .filter(|x| x.offset != item.offset)
+            .map(|x| *x.entry.name())
+            .collect::<Vec<_>>();
+        let short = match oem_short_name(&item.name, &self.codepage, &taken) {
+            Some(short) if short != *item.entry.name() => short,
+            _ => return Ok(false),
+        };
+        let mut entry = self.read_dir_entry(item.offset)?;
+        entry.0[..11].copy_from_slice(&short);
+        self.write_dir_entry(item.offset, &entry)?;
+        for offset in &item.lfn_offsets {
+            let mut lfn = self.read_dir_entry(*offset)?;
+            lfn.set_lfn_checksum(entry.short_name_checksum());
+            self.write_dir_entry(*offset, &lfn)?;
+        }
+        Ok(true)
+    }
+
+    /// Point the `..` entry of a directory to its parent, `parent_cluster` 0 is the root
+    pub fn set_dot_dot(&self, dir_cluster: u32, parent_cluster: u32) -> FatResult<()> {
+        let dot_dot = self
+            .read_dir(dir_cluster)?
+            .into_iter()
+            .find(|(_, entry)| entry.name() == b"..         ");
+        if let Some((offset, mut entry)) = dot_dot {
+            entry.set_first_cluster(parent_cluster);
+            self.write_dir_entry(offset, &entry)?;
+        }
+        Ok(())
+    }
+
+    /// The files of a directory with their names, the deleted entries, the volume label
+    /// and the broken long names are skipped. `.` and `..` are kept.
+    pub fn read_dir_items(&self, first_cluster: u32) -> FatResult<Vec<DirItem>> {
+        let mut items = Vec::new();
+        self.walk_dir_items(first_cluster, 0, |item, _| {
+            items.push(item);
+            true
+        })?;
+        Ok(items)
+    }
+
+    /// Description:
+    ///
+    /// Walk the files of a directory like [Volume::read_dir_items], from the entry with
+    /// the index `start` in the directory. `f` gets every file with the index of the entry
+    /// after it, where the next walk can start, and it stops the walk by returning false.
+    /// The entries before `start` are not read.
+    ///
+    /// An entry never moves in a directory, a deleted entry is only marked, so an index
+    /// stays valid while files are created and deleted.
+    pub fn walk_dir_items<F>(&self, first_cluster: u32, start: u64, mut f: F) -> FatResult<()>
+    where
+        F: FnMut(DirItem, u64) -> bool,
+    {
+        let mut builder = ItemBuilder::new(self.codepage);
+        // the index of the first entry of the region
+        let mut index = 0;
+        for (offset, len) in self.dir_regions(first_cluster)? {
+            let count = len / DIR_ENTRY_SIZE;
+            if index + count <= start {
+                index += count;
+                continue;
+            }
+            let skip = start.saturating_sub(index);
+            let mut buf = vec![0u8; ((count - skip) * DIR_ENTRY_SIZE) as usize];
+            self.read_at(offset + skip * DIR_ENTRY_SIZE, &mut buf)?;
+            for (i, bytes) in buf.chunks_exact(DIR_ENTRY_SIZE as usize).enumerate() {
+                let entry = RawDirEntry(bytes.try_into().unwrap());
+                if entry.is_end() {
+                    return Ok(());
+                }
+                let i = skip + i as u64;
+                if let Some(item) = builder.push(offset + i * DIR_ENTRY_SIZE, entry)
+                    && !f(item, index + i + 1)
+                {
+                    return Ok(());
+                }
+            }
+            index += count;
+        }
+        Ok(())
+    }
+
+    /// Find a file of the directory by its name
+    pub fn find(&self, dir_cluster: u32, name: &str) -> FatResult<Option<DirItem>> {
+        let mut found = None;
+        self.walk_dir_items(dir_cluster, 0, |item, _| {
+            if item.matches(name, &self.codepage) {
+                found = Some(item);
+            }
+            found.is_none()
+        })?;
+        Ok(found)
+    }
+
+    /// Description:
+    ///
+    /// The inode number of a file is the position of its short entry. It does not change
+    /// with a write or a remount, and an empty file without a cluster has one too. The
+    /// first cluster would survive a rename, but it changes when a file is emptied and
+    /// written again. The root has no entry, it is [ROOT_INO]. A rename moves the entry,
+    /// the super block keeps the number of the file then, see [crate::FatSuperBlock::ino].
+    pub fn ino(&self, offset: u64) -> usize {
+        (offset / DIR_ENTRY_SIZE) as usize
+    }
+}
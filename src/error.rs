@@ -0,0 +1,159 @@
+use core::fmt::{Display, Formatter};
+
+/// The errno values returned to the syscall layer
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    ENOENT = 2,
+    EIO = 5,
+    ENXIO = 6,
+    EEXIST = 17,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EFBIG = 27,
+    ENOSPC = 28,
+    EROFS = 30,
+    ENAMETOOLONG = 36,
+    ENOTEMPTY = 39,
+}
+
+impl Errno {
+    const ALL: [Errno; 12] = [
+        Errno::ENOENT,
+        Errno::EIO,
+        Errno::ENXIO,
+        Errno::EEXIST,
+        Errno::ENOTDIR,
+        Errno::EISDIR,
+        Errno::EINVAL,
+        Errno::EFBIG,
+        Errno::ENOSPC,
+        Errno::EROFS,
+        Errno::ENAMETOOLONG,
+        Errno::ENOTEMPTY,
+    ];
+
+    /// The message of the errno, it is also the error string of the vfs operations
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Errno::ENOENT => "No such file or directory",
+            Errno::EIO => "I/O error",
+            Errno::ENXIO => "No such device or address",
+            Errno::EEXIST => "File exists",
+            Errno::ENOTDIR => "Not a directory",
+            Errno::EISDIR => "Is a directory",
+            Errno::EINVAL => "Invalid argument",
+            Errno::EFBIG => "File too large",
+            Errno::ENOSPC => "No space left on device",
+            Errno::EROFS => "Read-only file system",
+            Errno::ENAMETOOLONG => "File name too long",
+            Errno::ENOTEMPTY => "Directory not empty",
+        }
+    }
+
+    /// Find the errno from the error string returned by a vfs operation
+    pub fn from_message(msg: &str) -> Option<Errno> {
+        Self::ALL.into_iter().find(|errno| errno.as_str() == msg)
+    }
+}
+
+/// The error of the [crate::fstype::FatDevice]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    /// the device failed to read at the offset
+    Read(usize),
+    /// the device failed to write at the offset
+    Write(usize),
+    /// seek before the start of the device
+    Seek,
+    UnexpectedEof,
+    WriteZero,
+    /// write to a read only device
+    ReadOnly,
+}
+
+impl fatfs::IoError for DeviceError {
+    fn is_interrupted(&self) -> bool {
+        false
+    }
+
+    fn new_unexpected_eof_error() -> Self {
+        DeviceError::UnexpectedEof
+    }
+
+    fn new_write_zero_error() -> Self {
+        DeviceError::WriteZero
+    }
+}
+
+/// Description:
+///
+/// The error of the fat operations. The error from fatfs is kept as it is, so the
+/// device error can still be found in `fatfs::Error::Io`.
+#[derive(Debug)]
+pub enum FatError {
+    Fs(fatfs::Error<DeviceError>),
+    /// the operation needs a directory
+    NotDir,
+    /// the operation needs a regular file
+    IsDir,
+    /// the file would be larger than the filesystem allows
+    FileTooBig,
+    InvalidArgument,
+    /// the filesystem is mounted read only
+    ReadOnly,
+    /// SEEK_DATA or SEEK_HOLE from the end of the file or after it
+    NoData,
+}
+
+pub type FatResult<T> = Result<T, FatError>;
+
+impl FatError {
+    pub fn errno(&self) -> Errno {
+        match self {
+            FatError::Fs(err) => match err {
+                fatfs::Error::NotFound => Errno::ENOENT,
+                fatfs::Error::AlreadyExists => Errno::EEXIST,
+                fatfs::Error::DirectoryIsNotEmpty => Errno::ENOTEMPTY,
+                fatfs::Error::NotEnoughSpace => Errno::ENOSPC,
+                fatfs::Error::InvalidFileNameLength => Errno::ENAMETOOLONG,
+                fatfs::Error::InvalidInput | fatfs::Error::UnsupportedFileNameCharacter => {
+                    Errno::EINVAL
+                }
+                fatfs::Error::Io(DeviceError::ReadOnly) => Errno::EROFS,
+                _ => Errno::EIO,
+            },
+            FatError::NotDir => Errno::ENOTDIR,
+            FatError::IsDir => Errno::EISDIR,
+            FatError::FileTooBig => Errno::EFBIG,
+            FatError::InvalidArgument => Errno::EINVAL,
+            FatError::ReadOnly => Errno::EROFS,
+            FatError::NoData => Errno::ENXIO,
+        }
+    }
+}
+
+impl From<fatfs::Error<DeviceError>> for FatError {
+    fn from(err: fatfs::Error<DeviceError>) -> Self {
+        FatError::Fs(err)
+    }
+}
+
+impl From<DeviceError> for FatError {
+    fn from(err: DeviceError) -> Self {
+        FatError::Fs(fatfs::Error::Io(err))
+    }
+}
+
+impl From<FatError> for &'static str {
+    fn from(err: FatError) -> Self {
+        err.errno().as_str()
+    }
+}
+
+impl Display for FatError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}: {:?}", self.errno().as_str(), self)
+    }
+}
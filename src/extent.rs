@@ -0,0 +1,113 @@
+use crate::disk::Volume;
+use crate::error::FatResult;
+use alloc::vec::Vec;
+use core::cmp::min;
+
+/// A run of contiguous clusters of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// the index of the first cluster of the run in the file
+    pub index: u32,
+    /// the first cluster of the run on disk
+    pub cluster: u32,
+    /// the number of clusters
+    pub len: u32,
+}
+
+/// Description:
+///
+/// The cluster chain of a file as a sorted list of [Extent]s. The chain is walked once
+/// when the map is built, then the cluster of any offset is found with a binary search
+/// instead of following the FAT from the first cluster.
+///
+/// The map does not follow the FAT, it must be built again when the chain changes.
+#[derive(Debug, Default)]
+pub struct ExtentMap {
+    first_cluster: u32,
+    extents: Vec<Extent>,
+    clusters: u32,
+}
+
+impl ExtentMap {
+    /// Walk the chain from `first_cluster`, 0 is an empty file
+    pub fn build(volume: &Volume, first_cluster: u32) -> FatResult<Self> {
+        let mut map = Self {
+            first_cluster,
+            ..Self::default()
+        };
+        if first_cluster == 0 {
+            return Ok(map);
+        }
+        for cluster in volume.chain(first_cluster)? {
+            match map.extents.last_mut() {
+                Some(last) if last.cluster + last.len == cluster => last.len += 1,
+                _ => map.extents.push(Extent {
+                    index: map.clusters,
+                    cluster,
+                    len: 1,
+                }),
+            }
+            map.clusters += 1;
+        }
+        Ok(map)
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+
+    /// The number of clusters of the chain
+    pub fn clusters(&self) -> u32 {
+        self.clusters
+    }
+
+    pub fn extents(&self) -> &[Extent] {
+        &self.extents
+    }
+
+    /// The cluster at `index` of the file and the number of clusters that follow it on
+    /// disk in the same run, itself included
+    pub fn find(&self, index: u32) -> Option<(u32, u32)> {
+        if index >= self.clusters {
+            return None;
+        }
+        let pos = self.extents.partition_point(|x| x.index <= index) - 1;
+        let extent = &self.extents[pos];
+        let skip = index - extent.index;
+        Some((extent.cluster + skip, extent.len - skip))
+    }
+
+    /// Description:
+    ///
+    /// Split the bytes `offset..offset + len` of the file into the byte ranges on disk,
+    /// `f` is called with the position on the volume, the position in the range and the
+    /// length of every piece. A piece never crosses the end of a run, so it can be read
+    /// or written with one I/O. The bytes after the chain are left out, the number of
+    /// bytes given to `f` is returned.
+    pub fn for_each_range<F>(
+        &self,
+        volume: &Volume,
+        offset: u64,
+        len: usize,
+        mut f: F,
+    ) -> FatResult<usize>
+    where
+        F: FnMut(u64, usize, usize) -> FatResult<()>,
+    {
+        let cluster_size = volume.cluster_size() as u64;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let index = pos / cluster_size;
+            if index >= self.clusters as u64 {
+                break;
+            }
+            let (cluster, run) = self.find(index as u32).unwrap();
+            let in_cluster = pos % cluster_size;
+            let n = min(run as u64 * cluster_size - in_cluster, (len - done) as u64) as usize;
+            f(volume.cluster_offset(cluster)? + in_cluster, done, n)?;
+            done += n;
+        }
+        Ok(done)
+    }
+}
@@ -1,12 +1,14 @@
-use crate::{get_fat_data, FatInodeType};
+use crate::disk::{fold_name, name_eq, trim_name, MAX_FILE_SIZE};
+use crate::error::{FatError, FatResult};
+use crate::inode::update_inode_times;
+use crate::{check_writable, get_fat_data, get_fat_sb, FatFile, FatInodeType};
 use alloc::sync::Arc;
-use alloc::vec;
-use core::cmp::max;
+use core::cmp::min;
 
-use fatfs::{Read, Seek, SeekFrom, Write};
+use fatfs::{Seek, SeekFrom, Write};
 use log::debug;
-use rvfs::dentry::{DirEntryOps, Dirent64, DirentType};
-use rvfs::file::{File, FileOps};
+use rvfs::dentry::{DirEntry, DirEntryOps, Dirent64, DirentType};
+use rvfs::file::{File, FileOps, SeekFrom as VfsSeekFrom};
 use rvfs::StrResult;
 pub const FAT_FILE_FILE_OPS: FileOps = {
     let mut file_ops = FileOps::empty();
@@ -14,180 +16,318 @@ pub const FAT_FILE_FILE_OPS: FileOps = {
     file_ops.write = fat_write_file;
     file_ops.open = |_| Ok(());
     file_ops.llseek = fat_llseek;
+    file_ops.flush = fat_flush;
+    file_ops.fsync = fat_fsync;
     file_ops
 };
 
 pub const FAT_DIR_FILE_OPS: FileOps = {
     let mut dir_ops = FileOps::empty();
     dir_ops.readdir = fat_readdir;
+    dir_ops.llseek = fat_dir_llseek;
     dir_ops.open = |_| Ok(());
     dir_ops.flush = fat_flush;
     dir_ops.fsync = fat_fsync;
     dir_ops
 };
 
-pub const FAT_DENTRY_OPS: DirEntryOps = DirEntryOps::empty();
+/// The zeros written to the gap when a file is extended
+static ZEROS: [u8; 4096] = [0; 4096];
+
+/// The dentry cache folds the names like vfat, the dots at the end are dropped and the
+/// characters are folded with [fold_name]
+pub const FAT_DENTRY_OPS: DirEntryOps = {
+    let mut ops = DirEntryOps::empty();
+    ops.d_hash = fat_d_hash;
+    ops.d_compare = fat_d_compare;
+    ops
+};
+
+/// FNV-1a of the folded name, the names that [fat_d_compare] finds equal have one hash
+fn fat_d_hash(_dentry: Arc<DirEntry>, name: &str) -> usize {
+    fold_name(trim_name(name)).fold(0xcbf2_9ce4_8422_2325u64, |hash, c| {
+        (hash ^ c as u64).wrapping_mul(0x100_0000_01b3)
+    }) as usize
+}
+
+fn fat_d_compare(_dentry: Arc<DirEntry>, name1: &str, name2: &str) -> bool {
+    name_eq(trim_name(name1), trim_name(name2))
+}
 
 fn fat_read_file(file: Arc<File>, buf: &mut [u8], offset: u64) -> StrResult<usize> {
     debug!("fat read {} {}", buf.len(), offset);
+    check_offset(offset)?;
     let inode = file.f_dentry.access_inner().d_inode.clone();
+    let sb_blk = inode.super_blk.upgrade().unwrap();
     let fat_data = get_fat_data(inode);
-    let _parent = &fat_data.parent;
-    return if let FatInodeType::File((_name, file)) = &fat_data.current {
-        if file.is_none() {
-            return Err("Open file failed");
-        }
-        let mut file = file.as_ref().unwrap().lock();
-        if file.offset() != offset as u32 {
-            file.seek(SeekFrom::Start(offset))
-                .map_err(|_| "Seek failed")?;
-        }
-        let mut buf = buf;
-        let mut count = 0;
-        while buf.len() > 0 {
-            let res = file.read(buf);
-            if res.is_err() {
-                return Err("Read file failed");
-            }
-            let len = res.unwrap();
-            if len == 0 {
-                break;
-            }
-            count += len;
-            buf = &mut buf[len..];
-        }
-        Ok(count)
-    } else {
-        Err("Not a file")
-    };
+    if !matches!(fat_data.current, FatInodeType::File(_)) {
+        return Err(FatError::IsDir.into());
+    }
+    // the reads dont share the fatfs file and its cursor, the entry on disk gives the
+    // first cluster and the size. It is saved after every write.
+    let volume = &get_fat_sb(&sb_blk).volume;
+    let entry = volume.read_dir_entry(fat_data.offset)?;
+    let map = fat_data.extent_map(volume, entry.first_cluster(), entry.size())?;
+    let count = volume.read_file(&map, entry.size(), offset, buf)?;
+    Ok(count)
 }
 fn fat_write_file(file: Arc<File>, buf: &[u8], offset: u64) -> StrResult<usize> {
-    // warn!("fat write {} {}",buf.len(),offset);
     let inode = file.f_dentry.access_inner().d_inode.clone();
-    let f_size = file
-        .f_dentry
-        .access_inner()
-        .d_inode
-        .access_inner()
-        .file_size;
-    let fat_data = get_fat_data(inode);
-    let _parent = &fat_data.parent;
-    return if let FatInodeType::File((_name, file)) = &fat_data.current {
-        if file.is_none() {
-            return Err("Open file failed");
-        }
-        let mut file = file.as_ref().unwrap().lock();
-
-        if f_size < offset as usize {
-            let max_offset = max(offset as usize, file.offset() as usize);
-            if max_offset > f_size {
-                let data = vec![0u8; max_offset - f_size];
-                file.write_all(&data).map_err(|_| "Write file failed")?;
-            }
-        }
+    check_writable(&inode)?;
+    check_offset(offset)?;
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    // the part of the data after the largest file is not written
+    if offset >= MAX_FILE_SIZE {
+        return Err(FatError::FileTooBig.into());
+    }
+    let buf = &buf[..min(buf.len() as u64, MAX_FILE_SIZE - offset) as usize];
+    let sb_blk = inode.super_blk.upgrade().unwrap();
+    let fat_data = get_fat_data(inode.clone());
+    return if let FatInodeType::File(_) = &fat_data.current {
+        let fat_sb = get_fat_sb(&sb_blk);
+        let volume = &fat_sb.volume;
+        let file = fat_data.fat_file(volume)?;
+        let mut file = file.lock();
 
-        if file.offset() != offset as u32 {
-            file.seek(SeekFrom::Start(offset))
-                .map_err(|_| "Seek file failed")?;
+        // the bytes inside the file are written to its clusters without seeking the
+        // fatfs file, only the bytes after the end need new clusters
+        let entry = volume.read_dir_entry(fat_data.offset)?;
+        let size = entry.size() as u64;
+        let mut done = 0;
+        if offset < size {
+            let map = fat_data.extent_map(volume, entry.first_cluster(), entry.size())?;
+            let len = min(buf.len() as u64, size - offset) as usize;
+            done = volume.write_file(&map, offset, &buf[..len])?;
         }
-        let res = file.write_all(buf);
-        if res.is_err() {
-            return Err("Write file failed");
+        if done == buf.len() {
+            // the size is the same, only the time changes
+            fat_data.data_changed(size, fat_sb);
+            update_inode_times(&inode);
+            return Ok(buf.len());
         }
+        let (rest, offset) = (&buf[done..], offset + done as u64);
+
+        // the gap after the end is filled with zeros
+        write_zeros(&mut file, min(offset, size), offset)?;
+        file.write_all(rest).map_err(FatError::from)?;
+        // save the size and the first cluster for the readers
+        file.flush().map_err(FatError::from)?;
+        fat_data.invalidate_extents();
+        let size = offset + rest.len() as u64;
+        inode.access_inner().file_size = size as usize;
+        fat_data.data_changed(size, fat_sb);
+        update_inode_times(&inode);
         Ok(buf.len())
     } else {
-        Err("Not a file")
+        Err(FatError::IsDir.into())
     };
 }
 
+/// The offset of a read or a write is a `loff_t`, a negative one is an invalid argument
+fn check_offset(offset: u64) -> FatResult<()> {
+    if offset > i64::MAX as u64 {
+        return Err(FatError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// Write zeros from `from` to `to` a chunk at a time, the file must not be shorter than
+/// `from`. The file is at `to` then.
+pub(crate) fn write_zeros(file: &mut FatFile, from: u64, to: u64) -> FatResult<()> {
+    file.seek(SeekFrom::Start(from))?;
+    let mut gap = to - from;
+    while gap > 0 {
+        let len = min(gap, ZEROS.len() as u64) as usize;
+        file.write_all(&ZEROS[..len])?;
+        gap -= len as u64;
+    }
+    Ok(())
+}
+
+/// The positions of `.` and `..` in a directory, the entries on disk come after them
+const DOT_ENTRIES: u64 = 2;
+
+/// Description:
+///
+/// Fill `dirents` with the files of the directory from the position of the file. `.` and
+/// `..` are the positions 0 and 1, the root has no entries for them and the ones on disk
+/// are skipped. Then the position is the index of an entry in the directory plus 2. It is
+/// the `d_off` of a dirent too, so `telldir`/`seekdir` cookies stay valid while files are
+/// created and deleted. Only the entries after the position are read.
+///
+/// An empty buffer asks for the size of the dirents of all the files after the position,
+/// the position does not move then.
 fn fat_readdir(file: Arc<File>, dirents: &mut [u8]) -> StrResult<usize> {
     let mut file_inner = file.access_inner();
-    let f_pos = file_inner.f_pos;
+    let f_pos = file_inner.f_pos as u64;
     let inode = file.f_dentry.access_inner().d_inode.clone();
+    let sb_blk = inode.super_blk.upgrade().unwrap();
+    let self_ino = inode.number as u64;
     let fat_data = get_fat_data(inode);
+    if !matches!(fat_data.current, FatInodeType::Dir(_)) {
+        return Err(FatError::NotDir.into());
+    }
+    // the root is its own parent
+    let parent_ino = fat_data
+        .parent_inode
+        .as_ref()
+        .map_or(self_ino, |x| x.number as u64);
+    let fat_sb = get_fat_sb(&sb_blk);
+    let volume = &fat_sb.volume;
 
-    let mut read_num = 0;
-    return if let FatInodeType::Dir(dir) = &fat_data.current {
-        let value = if dirents.is_empty() {
-            dir.lock()
-                .iter()
-                .map(|x| {
-                    if let Ok(x) = x {
-                        let fake_dirent = Dirent64::new(&x.file_name(), 1, 0, DirentType::empty());
-                        fake_dirent.len()
-                    } else {
-                        0
-                    }
-                })
-                .sum::<usize>()
-        } else {
-            let mut count = 0;
-            let buf_len = dirents.len();
-            let mut ptr = dirents.as_mut_ptr();
-            dir.lock()
-                .iter()
-                .skip(f_pos)
-                .enumerate()
-                .for_each(|(index, x)| {
-                    if let Ok(sub_file) = x {
-                        let type_ = if sub_file.is_file() {
-                            DirentType::DT_REG
-                        } else if sub_file.is_dir() {
-                            DirentType::DT_DIR
-                        } else {
-                            DirentType::empty()
-                        };
-                        let dirent = Dirent64::new(&sub_file.file_name(), 1, index as i64, type_);
-                        if count + dirent.len() <= buf_len {
-                            let dirent_ptr = unsafe { &mut *(ptr as *mut Dirent64) };
-                            *dirent_ptr = dirent;
-                            let name_ptr = dirent_ptr.name.as_mut_ptr();
-                            unsafe {
-                                let mut name = sub_file.file_name().clone();
-                                name.push('\0');
-                                let len = name.len();
-                                name_ptr.copy_from(name.as_ptr(), len);
-                                ptr = ptr.add(dirent_ptr.len());
-                            }
-                            count += dirent_ptr.len();
-                            read_num += 1;
-                        } else {
-                            return;
-                        }
-                    }
-                });
-            count
-        };
-        file_inner.f_pos += read_num;
-        Ok(value)
-    } else {
-        Err("Not a dir")
+    let query = dirents.is_empty();
+    let buf_len = dirents.len();
+    let mut ptr = dirents.as_mut_ptr();
+    let mut count = 0;
+    let mut pos = f_pos;
+    // add a dirent, false if the buffer is full
+    let mut emit = |name: &str, ino: u64, type_: DirentType, next: u64| {
+        let dirent = Dirent64::new(name, ino, next as i64, type_);
+        if query {
+            count += dirent.len();
+            return true;
+        }
+        if count + dirent.len() > buf_len {
+            return false;
+        }
+        let dirent_ptr = unsafe { &mut *(ptr as *mut Dirent64) };
+        *dirent_ptr = dirent;
+        let name_ptr = dirent_ptr.name.as_mut_ptr();
+        unsafe {
+            name_ptr.copy_from(name.as_ptr(), name.len());
+            *name_ptr.add(name.len()) = 0;
+            ptr = ptr.add(dirent_ptr.len());
+        }
+        count += dirent_ptr.len();
+        pos = next;
+        true
     };
+
+    let dots = [(".", self_ino), ("..", parent_ino)];
+    let mut full = false;
+    for (index, (name, ino)) in dots.into_iter().enumerate().skip(f_pos as usize) {
+        if !emit(name, ino, DirentType::DT_DIR, index as u64 + 1) {
+            full = true;
+            break;
+        }
+    }
+    let start = f_pos.saturating_sub(DOT_ENTRIES);
+    if !full {
+        // the entries on disk give the inode numbers, they are the same as the lookup
+        volume.walk_dir_items(fat_data.cluster, start, |item, next| {
+            if item.entry.is_dot() {
+                return true;
+            }
+            let type_ = if item.entry.is_dir() {
+                DirentType::DT_DIR
+            } else {
+                DirentType::DT_REG
+            };
+            let ino = fat_sb.ino(item.offset) as u64;
+            full = !emit(&item.name, ino, type_, next + DOT_ENTRIES);
+            !full
+        })?;
+    }
+    if !query {
+        file_inner.f_pos = pos as usize;
+    }
+    Ok(count)
 }
 
 fn fat_flush(file: Arc<File>) -> StrResult<()> {
     let inode = file.f_dentry.access_inner().d_inode.clone();
+    let sb_blk = inode.super_blk.upgrade().unwrap();
     let fat_data = get_fat_data(inode);
-    let _parent = &fat_data.parent;
-    return if let FatInodeType::File((_name, file)) = &fat_data.current {
-        if file.is_none() {
-            return Err("Open file failed");
-        }
-        let mut file = file.as_ref().unwrap().lock();
-        let res = file.flush();
-        if res.is_err() {
-            return Err("Flush file failed");
-        }
-        Ok(())
-    } else {
-        Err("Not a file")
-    };
+    // a file that has not been opened in fatfs has nothing of its own to flush, a
+    // directory only has its entry
+    if let Some(file) = fat_data.open_fat_file() {
+        file.lock().flush().map_err(FatError::from)?;
+    }
+    // the times of the inode go to the entry, it stays in the cache
+    fat_data.write_back(&get_fat_sb(&sb_blk).volume)?;
+    Ok(())
 }
 
 fn fat_fsync(file: Arc<File>, _datasync: bool) -> StrResult<()> {
-    fat_flush(file)
+    let sb_blk = file
+        .f_dentry
+        .access_inner()
+        .d_inode
+        .super_blk
+        .upgrade()
+        .unwrap();
+    fat_flush(file)?;
+    // the device flush of fatfs keeps the blocks in the cache
+    get_fat_sb(&sb_blk).sync()?;
+    Ok(())
+}
+
+/// The size of a regular file from its entry on disk, a directory has no size to seek in
+fn file_size(file: &File) -> FatResult<u64> {
+    let inode = file.f_dentry.access_inner().d_inode.clone();
+    let sb_blk = inode.super_blk.upgrade().unwrap();
+    let fat_data = get_fat_data(inode);
+    if !matches!(fat_data.current, FatInodeType::File(_)) {
+        return Err(FatError::InvalidArgument);
+    }
+    let entry = get_fat_sb(&sb_blk).volume.read_dir_entry(fat_data.offset)?;
+    Ok(entry.size() as u64)
+}
+
+/// Description:
+///
+/// Move the position of a directory for `seekdir` and `telldir`. The position is a cookie
+/// of a dirent from readdir, or 0 for the start, so only `Start` and `Current(0)` are
+/// taken and the others are an invalid argument.
+fn fat_dir_llseek(file: Arc<File>, whence: VfsSeekFrom) -> StrResult<u64> {
+    let mut file_inner = file.access_inner();
+    let pos = match whence {
+        VfsSeekFrom::Start(pos) if pos <= i64::MAX as u64 => pos,
+        VfsSeekFrom::Current(0) => file_inner.f_pos as u64,
+        _ => return Err(FatError::InvalidArgument.into()),
+    };
+    file_inner.f_pos = pos as usize;
+    Ok(pos)
+}
+
+/// Move the position of the file, a position before the start or after the largest file
+/// is an invalid argument
+fn fat_llseek(file: Arc<File>, whence: VfsSeekFrom) -> StrResult<u64> {
+    let size = file_size(&file)?;
+    let mut file_inner = file.access_inner();
+    let pos = match whence {
+        VfsSeekFrom::Start(pos) => Some(pos),
+        VfsSeekFrom::End(delta) => size.checked_add_signed(delta),
+        VfsSeekFrom::Current(delta) => (file_inner.f_pos as u64).checked_add_signed(delta),
+    };
+    let pos = pos
+        .filter(|pos| *pos <= MAX_FILE_SIZE)
+        .ok_or(FatError::InvalidArgument)?;
+    file_inner.f_pos = pos as usize;
+    Ok(pos)
+}
+
+/// Description:
+///
+/// `SEEK_DATA` of Linux, rvfs has no whence for it. FAT has no holes, so the data starts
+/// at `offset` itself. There is no data at or after the end of the file.
+pub fn fat_seek_data(file: Arc<File>, offset: i64) -> StrResult<u64> {
+    let size = file_size(&file)?;
+    if offset < 0 || offset as u64 >= size {
+        return Err(FatError::NoData.into());
+    }
+    file.access_inner().f_pos = offset as usize;
+    Ok(offset as u64)
 }
 
-fn fat_llseek(_file: Arc<File>, _whence: rvfs::file::SeekFrom) -> StrResult<u64> {
-    Err("Not support")
+/// `SEEK_HOLE` of Linux, the only hole of a FAT file is the one at its end
+pub fn fat_seek_hole(file: Arc<File>, offset: i64) -> StrResult<u64> {
+    let size = file_size(&file)?;
+    if offset < 0 || offset as u64 >= size {
+        return Err(FatError::NoData.into());
+    }
+    file.access_inner().f_pos = size as usize;
+    Ok(size)
 }
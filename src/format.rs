@@ -0,0 +1,364 @@
+use crate::boot_sector::{BOOT_SIGNATURE, FAT12_MAX_CLUSTERS, FAT16_MAX_CLUSTERS};
+use crate::error::{FatError, FatResult};
+use crate::fstype::FatDevice;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::cmp::min;
+use fatfs::{FatType, Seek, SeekFrom, Write};
+use rvfs::superblock::Device;
+
+const FAT32_MIN_CLUSTERS: u32 = FAT16_MAX_CLUSTERS + 1;
+const FAT32_MAX_CLUSTERS: u32 = 0x0FFF_FFF4;
+const MAX_CLUSTER_SIZE: u32 = 64 * 1024;
+const FAT32_FS_INFO_SECTOR: u16 = 1;
+const FAT32_BACKUP_BOOT_SECTOR: u16 = 6;
+const FAT32_ROOT_CLUSTER: u32 = 2;
+
+/// Description:
+///
+/// The options of [format_volume]. It is a builder, the fields that are not set are
+/// chosen from the size of the device like `mkfs.vfat` does.
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    fat_type: Option<FatType>,
+    bytes_per_sector: u16,
+    bytes_per_cluster: Option<u32>,
+    volume_label: [u8; 11],
+    volume_id: u32,
+    fats: u8,
+    reserved_sectors: Option<u16>,
+    root_entries: Option<u16>,
+    oem_name: [u8; 8],
+    media: u8,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            fat_type: None,
+            bytes_per_sector: 512,
+            bytes_per_cluster: None,
+            volume_label: *b"NO NAME    ",
+            volume_id: 0x1234_5678,
+            fats: 2,
+            reserved_sectors: None,
+            root_entries: None,
+            oem_name: *b"MSWIN4.1",
+            media: 0xF8,
+        }
+    }
+}
+
+impl FormatOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn fat_type(mut self, fat_type: FatType) -> Self {
+        self.fat_type = Some(fat_type);
+        self
+    }
+    /// 512, 1024, 2048 or 4096
+    pub fn bytes_per_sector(mut self, bytes_per_sector: u16) -> Self {
+        self.bytes_per_sector = bytes_per_sector;
+        self
+    }
+    /// A power of two from the sector size to 64 KiB
+    pub fn bytes_per_cluster(mut self, bytes_per_cluster: u32) -> Self {
+        self.bytes_per_cluster = Some(bytes_per_cluster);
+        self
+    }
+    /// The label is padded with spaces, it can have 11 bytes at most
+    pub fn volume_label(mut self, label: &str) -> FatResult<Self> {
+        if label.len() > 11 || !label.is_ascii() {
+            return Err(FatError::InvalidArgument);
+        }
+        self.volume_label = [b' '; 11];
+        self.volume_label[..label.len()].copy_from_slice(label.to_ascii_uppercase().as_bytes());
+        Ok(self)
+    }
+    pub fn volume_id(mut self, volume_id: u32) -> Self {
+        self.volume_id = volume_id;
+        self
+    }
+    pub fn fats(mut self, fats: u8) -> Self {
+        self.fats = fats;
+        self
+    }
+    pub fn reserved_sectors(mut self, reserved_sectors: u16) -> Self {
+        self.reserved_sectors = Some(reserved_sectors);
+        self
+    }
+    /// The entries of the fixed root directory of fat12/16
+    pub fn root_entries(mut self, root_entries: u16) -> Self {
+        self.root_entries = Some(root_entries);
+        self
+    }
+    /// The name is padded with spaces, it can have 8 bytes at most
+    pub fn oem_name(mut self, name: &str) -> FatResult<Self> {
+        if name.len() > 8 || !name.is_ascii() {
+            return Err(FatError::InvalidArgument);
+        }
+        self.oem_name = [b' '; 8];
+        self.oem_name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(self)
+    }
+}
+
+/// The layout computed from the options and the device size
+struct Layout {
+    fat_type: FatType,
+    bytes_per_sector: u32,
+    sectors_per_cluster: u32,
+    reserved_sectors: u32,
+    root_entries: u32,
+    total_sectors: u32,
+    sectors_per_fat: u32,
+    clusters: u32,
+}
+
+impl Layout {
+    fn root_dir_sectors(&self) -> u32 {
+        (self.root_entries * 32).div_ceil(self.bytes_per_sector)
+    }
+    fn first_root_dir_sector(&self, fats: u8) -> u32 {
+        self.reserved_sectors + fats as u32 * self.sectors_per_fat
+    }
+    fn first_data_sector(&self, fats: u8) -> u32 {
+        self.first_root_dir_sector(fats) + self.root_dir_sectors()
+    }
+}
+
+/// Format the whole device as a new fat volume
+pub fn format_volume(device: Arc<dyn Device>, options: FormatOptions) -> FatResult<()> {
+    let mut fat_device = FatDevice::new(device);
+    let layout = compute_layout(&options, fat_device.size())?;
+    let bps = layout.bytes_per_sector as u64;
+    let fats = options.fats;
+
+    // clean the reserved sectors, the FATs and the root directory
+    let metadata_end = layout.first_data_sector(fats) as u64 * bps;
+    write_zeros(&mut fat_device, 0, metadata_end)?;
+
+    let boot_sector = boot_sector(&options, &layout);
+    fat_device.write_at(0, &boot_sector)?;
+    if layout.fat_type == FatType::Fat32 {
+        let fs_info = fs_info_sector(&layout);
+        fat_device.write_at(FAT32_FS_INFO_SECTOR as u64 * bps, &fs_info)?;
+        let backup = FAT32_BACKUP_BOOT_SECTOR as u64 * bps;
+        fat_device.write_at(backup, &boot_sector)?;
+        fat_device.write_at(backup + bps, &fs_info)?;
+    }
+
+    // the first two entries are reserved, fat32 also uses the first cluster for root
+    let fat_head: &[u8] = match layout.fat_type {
+        FatType::Fat12 => &[options.media, 0xFF, 0xFF],
+        FatType::Fat16 => &[options.media, 0xFF, 0xFF, 0xFF],
+        FatType::Fat32 => &[
+            options.media,
+            0xFF,
+            0xFF,
+            0x0F,
+            0xFF,
+            0xFF,
+            0xFF,
+            0x0F,
+            0xFF,
+            0xFF,
+            0xFF,
+            0x0F,
+        ],
+    };
+    for i in 0..fats as u32 {
+        let fat_offset = (layout.reserved_sectors + i * layout.sectors_per_fat) as u64 * bps;
+        fat_device.write_at(fat_offset, fat_head)?;
+    }
+
+    let root_offset = if layout.fat_type == FatType::Fat32 {
+        let offset = layout.first_data_sector(fats) as u64 * bps;
+        let cluster_size = (layout.sectors_per_cluster * layout.bytes_per_sector) as u64;
+        write_zeros(&mut fat_device, offset, cluster_size)?;
+        offset
+    } else {
+        layout.first_root_dir_sector(fats) as u64 * bps
+    };
+    if options.volume_label != *b"NO NAME    " {
+        // the label is also saved as the first entry of the root directory
+        let mut entry = [0u8; 32];
+        entry[..11].copy_from_slice(&options.volume_label);
+        entry[11] = 0x08;
+        fat_device.write_at(root_offset, &entry)?;
+    }
+    fat_device.cache().flush()?;
+    Ok(())
+}
+
+fn compute_layout(options: &FormatOptions, device_size: u64) -> FatResult<Layout> {
+    let bps = options.bytes_per_sector as u32;
+    if !matches!(bps, 512 | 1024 | 2048 | 4096) || options.fats == 0 {
+        return Err(FatError::InvalidArgument);
+    }
+    let total_sectors = min(device_size / bps as u64, u32::MAX as u64) as u32;
+    let fat_type = options.fat_type.unwrap_or({
+        let size = total_sectors as u64 * bps as u64;
+        if size >= 512 * 1024 * 1024 {
+            FatType::Fat32
+        } else if size >= 16 * 1024 * 1024 {
+            FatType::Fat16
+        } else {
+            FatType::Fat12
+        }
+    });
+    let (min_clusters, max_clusters) = match fat_type {
+        FatType::Fat12 => (1, FAT12_MAX_CLUSTERS),
+        FatType::Fat16 => (FAT12_MAX_CLUSTERS + 1, FAT16_MAX_CLUSTERS),
+        FatType::Fat32 => (FAT32_MIN_CLUSTERS, FAT32_MAX_CLUSTERS),
+    };
+    let reserved_sectors = options
+        .reserved_sectors
+        .unwrap_or(if fat_type == FatType::Fat32 { 32 } else { 1 })
+        as u32;
+    let root_entries = match fat_type {
+        FatType::Fat32 => 0,
+        _ => options.root_entries.unwrap_or(512) as u32,
+    };
+    if reserved_sectors == 0
+        || (fat_type == FatType::Fat32 && reserved_sectors <= FAT32_BACKUP_BOOT_SECTOR as u32 + 1)
+        || (fat_type != FatType::Fat32 && root_entries == 0)
+    {
+        return Err(FatError::InvalidArgument);
+    }
+    let layout_for = |sectors_per_cluster: u32| -> Option<Layout> {
+        let mut layout = Layout {
+            fat_type,
+            bytes_per_sector: bps,
+            sectors_per_cluster,
+            reserved_sectors,
+            root_entries,
+            total_sectors,
+            sectors_per_fat: 1,
+            clusters: 0,
+        };
+        // the FAT size depends on the cluster count and the other way round, grow the
+        // FAT until it can hold all clusters
+        loop {
+            let data_sectors = total_sectors.checked_sub(layout.first_data_sector(options.fats))?;
+            layout.clusters = data_sectors / sectors_per_cluster;
+            let fat_bytes = match fat_type {
+                FatType::Fat12 => ((layout.clusters + 2) * 3).div_ceil(2),
+                FatType::Fat16 => (layout.clusters + 2) * 2,
+                FatType::Fat32 => (layout.clusters + 2) * 4,
+            };
+            let sectors_per_fat = fat_bytes.div_ceil(bps);
+            if sectors_per_fat <= layout.sectors_per_fat {
+                break;
+            }
+            layout.sectors_per_fat = sectors_per_fat;
+        }
+        (min_clusters..=max_clusters)
+            .contains(&layout.clusters)
+            .then_some(layout)
+    };
+    if let Some(bytes_per_cluster) = options.bytes_per_cluster {
+        if !bytes_per_cluster.is_power_of_two()
+            || bytes_per_cluster < bps
+            || bytes_per_cluster > MAX_CLUSTER_SIZE
+        {
+            return Err(FatError::InvalidArgument);
+        }
+        return layout_for(bytes_per_cluster / bps).ok_or(FatError::InvalidArgument);
+    }
+    // the smallest cluster that keeps the cluster count in the range of the fat type,
+    // fat32 starts from 4 KiB clusters like mkfs.vfat
+    let mut cluster_size = match fat_type {
+        FatType::Fat32 => 4096u32.max(bps),
+        _ => bps,
+    };
+    while cluster_size <= MAX_CLUSTER_SIZE {
+        let sectors_per_cluster = cluster_size / bps;
+        let data_sectors = total_sectors.saturating_sub(reserved_sectors);
+        if data_sectors / sectors_per_cluster <= max_clusters {
+            if let Some(layout) = layout_for(sectors_per_cluster) {
+                return Ok(layout);
+            }
+            // too few clusters, the cluster must be smaller
+            if fat_type == FatType::Fat32 && sectors_per_cluster > 1 {
+                return layout_for(1).ok_or(FatError::InvalidArgument);
+            }
+            break;
+        }
+        cluster_size *= 2;
+    }
+    Err(FatError::InvalidArgument)
+}
+
+fn boot_sector(options: &FormatOptions, layout: &Layout) -> vec::Vec<u8> {
+    let mut buf = vec![0u8; layout.bytes_per_sector as usize];
+    let is_fat32 = layout.fat_type == FatType::Fat32;
+    let jump: [u8; 3] = if is_fat32 {
+        [0xEB, 0x58, 0x90]
+    } else {
+        [0xEB, 0x3C, 0x90]
+    };
+    buf[0..3].copy_from_slice(&jump);
+    buf[3..11].copy_from_slice(&options.oem_name);
+    buf[11..13].copy_from_slice(&(layout.bytes_per_sector as u16).to_le_bytes());
+    buf[13] = layout.sectors_per_cluster as u8;
+    buf[14..16].copy_from_slice(&(layout.reserved_sectors as u16).to_le_bytes());
+    buf[16] = options.fats;
+    buf[17..19].copy_from_slice(&(layout.root_entries as u16).to_le_bytes());
+    if layout.total_sectors < 0x10000 && !is_fat32 {
+        buf[19..21].copy_from_slice(&(layout.total_sectors as u16).to_le_bytes());
+    } else {
+        buf[32..36].copy_from_slice(&layout.total_sectors.to_le_bytes());
+    }
+    buf[21] = options.media;
+    // a fake geometry, nobody uses it now
+    buf[24..26].copy_from_slice(&32u16.to_le_bytes());
+    buf[26..28].copy_from_slice(&64u16.to_le_bytes());
+    let ext = if is_fat32 {
+        buf[36..40].copy_from_slice(&layout.sectors_per_fat.to_le_bytes());
+        buf[44..48].copy_from_slice(&FAT32_ROOT_CLUSTER.to_le_bytes());
+        buf[48..50].copy_from_slice(&FAT32_FS_INFO_SECTOR.to_le_bytes());
+        buf[50..52].copy_from_slice(&FAT32_BACKUP_BOOT_SECTOR.to_le_bytes());
+        64
+    } else {
+        buf[22..24].copy_from_slice(&(layout.sectors_per_fat as u16).to_le_bytes());
+        36
+    };
+    // the extended boot record
+    buf[ext] = 0x80;
+    buf[ext + 2] = 0x29;
+    buf[ext + 3..ext + 7].copy_from_slice(&options.volume_id.to_le_bytes());
+    buf[ext + 7..ext + 18].copy_from_slice(&options.volume_label);
+    let fs_type: &[u8; 8] = match layout.fat_type {
+        FatType::Fat12 => b"FAT12   ",
+        FatType::Fat16 => b"FAT16   ",
+        FatType::Fat32 => b"FAT32   ",
+    };
+    buf[ext + 18..ext + 26].copy_from_slice(fs_type);
+    buf[510..512].copy_from_slice(&BOOT_SIGNATURE);
+    buf
+}
+
+fn fs_info_sector(layout: &Layout) -> vec::Vec<u8> {
+    let mut buf = vec![0u8; layout.bytes_per_sector as usize];
+    buf[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    buf[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    // the root directory uses the first cluster
+    buf[488..492].copy_from_slice(&(layout.clusters - 1).to_le_bytes());
+    buf[492..496].copy_from_slice(&(FAT32_ROOT_CLUSTER + 1).to_le_bytes());
+    buf[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+    buf
+}
+
+fn write_zeros(device: &mut FatDevice, offset: u64, len: u64) -> FatResult<()> {
+    let zeros = vec![0u8; 64 * 1024];
+    device.seek(SeekFrom::Start(offset))?;
+    let mut left = len;
+    while left > 0 {
+        let n = min(left, zeros.len() as u64) as usize;
+        device.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+    Ok(())
+}
@@ -0,0 +1,494 @@
+use crate::disk::{LfnBuilder, RawDirEntry, Volume};
+use crate::error::FatResult;
+use crate::fstype::FatDevice;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use fatfs::FatType;
+use log::{info, warn};
+
+/// The FSInfo free cluster count is unknown
+const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// A problem found by [check]. The path of a file is made of its long names, or the short
+/// names if it has none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// a copy of the FAT differs from the first one
+    FatMismatch { fat: u8 },
+    /// the chain points to a free, a bad or an invalid cluster, or it has a loop
+    BadChain { path: String, cluster: u32 },
+    /// the cluster is already used by another file
+    CrossLink { path: String, cluster: u32 },
+    /// the size of the file does not agree with the length of its chain
+    SizeMismatch {
+        path: String,
+        size: u32,
+        clusters: u32,
+    },
+    /// the `.` or `..` entry of a directory is missing or points to a wrong cluster
+    BadDotEntries { path: String },
+    /// long name entries that do not belong to the short entry after them
+    OrphanLfn { dir: String, offset: u64 },
+    /// clusters that are used but no file owns them
+    LostChain { first_cluster: u32, clusters: u32 },
+    /// the free cluster count of the FSInfo sector is wrong
+    FsInfoFreeCount { stored: u32, actual: u32 },
+}
+
+/// The result of [check]
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    pub problems: Vec<FsckProblem>,
+    /// something was written to the volume
+    pub repaired: bool,
+    pub files: usize,
+    pub dirs: usize,
+    pub free_clusters: u32,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Description:
+///
+/// Check the FAT and the directory tree of a volume, like `fsck.fat`. With `repair`
+/// the problems are fixed when it is possible:
+/// - the other FATs are copied from the first one
+/// - a bad or cross-linked chain is cut before the bad cluster
+/// - a chain longer than the file is cut, a file longer than its chain is shrunk
+/// - the cluster of `.` and `..` is corrected
+/// - orphaned long name entries are deleted
+/// - lost chains are freed
+/// - the FSInfo free count is set to the real value
+///
+/// The volume must not be mounted with repair, fatfs would not see the changes.
+pub fn check(device: FatDevice, repair: bool) -> FatResult<FsckReport> {
+    let volume = Volume::new(device)?;
+    let mut checker = Checker {
+        fat: volume.read_fat(0)?,
+        owner: vec![0; volume.max_cluster() as usize + 1],
+        next_owner: 1,
+        volume,
+        repair,
+        report: FsckReport::default(),
+    };
+    checker.check_fat_copies()?;
+    checker.check_tree()?;
+    checker.check_lost_chains()?;
+    checker.check_fs_info()?;
+    if checker.report.repaired {
+        checker.volume.flush()?;
+    }
+    for problem in checker.report.problems.iter() {
+        warn!("fsck: {:?}", problem);
+    }
+    info!(
+        "fsck: {} files, {} dirs, {} problems",
+        checker.report.files,
+        checker.report.dirs,
+        checker.report.problems.len()
+    );
+    Ok(checker.report)
+}
+
+struct Checker {
+    volume: Volume,
+    repair: bool,
+    // the first FAT, it is updated by the repairs
+    fat: Vec<u32>,
+    // the id of the chain that uses the cluster, 0 is free
+    owner: Vec<u32>,
+    next_owner: u32,
+    report: FsckReport,
+}
+
+// a directory waiting to be checked
+struct PendingDir {
+    cluster: u32,
+    parent: u32,
+    path: String,
+}
+
+impl Checker {
+    fn problem(&mut self, problem: FsckProblem) {
+        self.report.problems.push(problem);
+    }
+
+    fn set_fat(&mut self, cluster: u32, value: u32) -> FatResult<()> {
+        self.volume.set_fat_entry(cluster, value)?;
+        self.fat[cluster as usize] = value;
+        self.report.repaired = true;
+        Ok(())
+    }
+
+    fn write_entry(&mut self, offset: u64, entry: &RawDirEntry) -> FatResult<()> {
+        self.volume.write_dir_entry(offset, entry)?;
+        self.report.repaired = true;
+        Ok(())
+    }
+
+    fn check_fat_copies(&mut self) -> FatResult<()> {
+        let first = self.volume.read_fat_bytes(0)?;
+        for fat in 1..self.volume.boot_sector().fats {
+            if self.volume.read_fat_bytes(fat)? != first {
+                self.problem(FsckProblem::FatMismatch { fat });
+                if self.repair {
+                    self.volume.write_at(self.volume.fat_offset(fat), &first)?;
+                    self.report.repaired = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark the chain as used by a new owner. The chain stops before a bad cluster or a
+    /// cluster of another owner, it is cut there with repair.
+    fn claim_chain(&mut self, first_cluster: u32, path: &str) -> FatResult<Vec<u32>> {
+        let id = self.next_owner;
+        self.next_owner += 1;
+        let mut clusters: Vec<u32> = Vec::new();
+        let mut cluster = first_cluster;
+        loop {
+            let problem = if !self.volume.is_data_cluster(cluster)
+                || self.fat[cluster as usize] == 0
+                || self.volume.is_bad_cluster(self.fat[cluster as usize])
+                || self.owner[cluster as usize] == id
+            {
+                Some(FsckProblem::BadChain {
+                    path: path.into(),
+                    cluster,
+                })
+            } else if self.owner[cluster as usize] != 0 {
+                Some(FsckProblem::CrossLink {
+                    path: path.into(),
+                    cluster,
+                })
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                self.problem(problem);
+                if self.repair
+                    && let Some(last) = clusters.last()
+                {
+                    self.set_fat(*last, self.volume.end_of_chain())?;
+                }
+                return Ok(clusters);
+            }
+            self.owner[cluster as usize] = id;
+            clusters.push(cluster);
+            let next = self.fat[cluster as usize];
+            if self.volume.is_end_of_chain(next) {
+                return Ok(clusters);
+            }
+            cluster = next;
+        }
+    }
+
+    fn check_tree(&mut self) -> FatResult<()> {
+        let root_cluster = self.volume.root_cluster();
+        let mut root_regions = Vec::new();
+        if self.volume.fat_type() == FatType::Fat32 {
+            let clusters = self.claim_chain(root_cluster, "/")?;
+            let cluster_size = self.volume.cluster_size() as u64;
+            root_regions = clusters
+                .iter()
+                .map(|x| Ok((self.volume.cluster_offset(*x)?, cluster_size)))
+                .collect::<FatResult<_>>()?;
+        } else {
+            root_regions.extend(self.volume.dir_regions(0)?);
+        }
+        self.report.dirs += 1;
+        let mut dirs = Vec::new();
+        self.check_dir(&root_regions, None, &mut dirs)?;
+        while let Some(dir) = dirs.pop() {
+            let clusters = self.owned_chain(dir.cluster);
+            let cluster_size = self.volume.cluster_size() as u64;
+            let regions = clusters
+                .iter()
+                .map(|x| Ok((self.volume.cluster_offset(*x)?, cluster_size)))
+                .collect::<FatResult<Vec<_>>>()?;
+            self.check_dir(&regions, Some(&dir), &mut dirs)?;
+        }
+        Ok(())
+    }
+
+    // the clusters of a chain that is already claimed
+    fn owned_chain(&self, first_cluster: u32) -> Vec<u32> {
+        let id = self.owner[first_cluster as usize];
+        let mut clusters = Vec::new();
+        let mut cluster = first_cluster;
+        while self.volume.is_data_cluster(cluster) && self.owner[cluster as usize] == id {
+            clusters.push(cluster);
+            if clusters.len() > self.fat.len() {
+                break;
+            }
+            cluster = self.fat[cluster as usize];
+        }
+        clusters
+    }
+
+    /// Check the entries of a directory, `dir` is none for the root
+    fn check_dir(
+        &mut self,
+        regions: &[(u64, u64)],
+        dir: Option<&PendingDir>,
+        dirs: &mut Vec<PendingDir>,
+    ) -> FatResult<()> {
+        let entries = self.volume.read_entries(regions)?;
+        let dir_path = dir.map_or("", |x| x.path.as_str());
+        if let Some(dir) = dir {
+            self.check_dot_entries(&entries, dir)?;
+        }
+        let mut lfn = LfnBuilder::default();
+        for (offset, entry) in entries.iter() {
+            if entry.is_deleted() {
+                self.drop_lfn(&mut lfn, dir_path)?;
+                continue;
+            }
+            if entry.is_lfn() {
+                if entry.lfn_is_last() || !lfn.accepts(entry) {
+                    self.drop_lfn(&mut lfn, dir_path)?;
+                }
+                if entry.lfn_is_last() {
+                    lfn.start(*offset, entry);
+                } else {
+                    lfn.push(*offset, entry);
+                }
+                continue;
+            }
+            let name = match lfn.name_for(entry) {
+                Some(name) => name,
+                None => {
+                    self.drop_lfn(&mut lfn, dir_path)?;
+                    entry.short_name(&self.volume.codepage())
+                }
+            };
+            lfn = LfnBuilder::default();
+            if entry.is_volume_label() || entry.is_dot() {
+                continue;
+            }
+            let path = format!("{}/{}", dir_path, name);
+            self.check_entry(*offset, *entry, path, dir, dirs)?;
+        }
+        self.drop_lfn(&mut lfn, dir_path)
+    }
+
+    fn check_entry(
+        &mut self,
+        offset: u64,
+        mut entry: RawDirEntry,
+        path: String,
+        dir: Option<&PendingDir>,
+        dirs: &mut Vec<PendingDir>,
+    ) -> FatResult<()> {
+        let cluster_size = self.volume.cluster_size();
+        let first_cluster = entry.first_cluster();
+        if entry.is_dir() {
+            self.report.dirs += 1;
+            let clusters = if first_cluster == 0 {
+                self.problem(FsckProblem::BadChain {
+                    path: path.clone(),
+                    cluster: 0,
+                });
+                Vec::new()
+            } else {
+                self.claim_chain(first_cluster, &path)?
+            };
+            if clusters.is_empty() {
+                // a directory without clusters can not be fixed
+                if self.repair {
+                    entry.delete();
+                    self.write_entry(offset, &entry)?;
+                }
+                return Ok(());
+            }
+            dirs.push(PendingDir {
+                cluster: first_cluster,
+                parent: dir.map_or(0, |x| x.cluster),
+                path,
+            });
+            return Ok(());
+        }
+        self.report.files += 1;
+        let mut clusters = if first_cluster == 0 {
+            Vec::new()
+        } else {
+            self.claim_chain(first_cluster, &path)?
+        };
+        let needed = entry.size().div_ceil(cluster_size) as usize;
+        // the first cluster is bad, the problem is already reported
+        let broken = first_cluster != 0 && clusters.is_empty();
+        if needed != clusters.len() {
+            self.problem(FsckProblem::SizeMismatch {
+                path,
+                size: entry.size(),
+                clusters: clusters.len() as u32,
+            });
+        } else if !broken {
+            return Ok(());
+        }
+        if !self.repair {
+            return Ok(());
+        }
+        if clusters.len() > needed {
+            // free the clusters after the end of the file
+            for cluster in clusters.drain(needed..) {
+                self.owner[cluster as usize] = 0;
+                self.set_fat(cluster, 0)?;
+            }
+            if let Some(last) = clusters.last() {
+                self.set_fat(*last, self.volume.end_of_chain())?;
+            }
+        } else {
+            entry.set_size(clusters.len() as u32 * cluster_size);
+        }
+        if clusters.is_empty() {
+            entry.set_first_cluster(0);
+        }
+        self.write_entry(offset, &entry)
+    }
+
+    /// A subdirectory starts with `.` pointing to itself and `..` pointing to the parent,
+    /// the parent is 0 if it is the root
+    fn check_dot_entries(
+        &mut self,
+        entries: &[(u64, RawDirEntry)],
+        dir: &PendingDir,
+    ) -> FatResult<()> {
+        let expected: [(&[u8; 11], u32); 2] =
+            [(b".          ", dir.cluster), (b"..         ", dir.parent)];
+        let mut bad = false;
+        for (i, (name, cluster)) in expected.into_iter().enumerate() {
+            let Some((offset, entry)) = entries.get(i) else {
+                bad = true;
+                continue;
+            };
+            if entry.name() != name || !entry.is_dir() {
+                bad = true;
+            } else if entry.first_cluster() != cluster {
+                bad = true;
+                if self.repair {
+                    let mut entry = *entry;
+                    entry.set_first_cluster(cluster);
+                    self.write_entry(*offset, &entry)?;
+                }
+            }
+        }
+        if bad {
+            self.problem(FsckProblem::BadDotEntries {
+                path: dir.path.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    // the long name entries are not followed by their short entry
+    fn drop_lfn(&mut self, lfn: &mut LfnBuilder, dir: &str) -> FatResult<()> {
+        let offsets = core::mem::take(&mut lfn.offsets);
+        *lfn = LfnBuilder::default();
+        let Some(first) = offsets.first() else {
+            return Ok(());
+        };
+        self.problem(FsckProblem::OrphanLfn {
+            dir: if dir.is_empty() {
+                "/".into()
+            } else {
+                dir.into()
+            },
+            offset: *first,
+        });
+        if self.repair {
+            for offset in offsets {
+                let mut entry = self.volume.read_dir_entry(offset)?;
+                entry.delete();
+                self.write_entry(offset, &entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The used clusters that are not in any chain are grouped into chains from the
+    /// clusters that nobody points to
+    fn check_lost_chains(&mut self) -> FatResult<()> {
+        let max_cluster = self.volume.max_cluster();
+        let is_lost = |checker: &Self, cluster: u32| {
+            let value = checker.fat[cluster as usize];
+            checker.owner[cluster as usize] == 0
+                && value != 0
+                && !checker.volume.is_bad_cluster(value)
+        };
+        let mut pointed = vec![false; max_cluster as usize + 1];
+        for cluster in 2..=max_cluster {
+            let next = self.fat[cluster as usize];
+            if is_lost(self, cluster) && self.volume.is_data_cluster(next) {
+                pointed[next as usize] = true;
+            }
+        }
+        // the heads first, the rest are loops
+        let heads = (2..=max_cluster).filter(|x| !pointed[*x as usize]);
+        let starts: Vec<u32> = heads.chain(2..=max_cluster).collect();
+        for first_cluster in starts {
+            if !is_lost(self, first_cluster) {
+                continue;
+            }
+            let id = self.next_owner;
+            self.next_owner += 1;
+            let mut chain = Vec::new();
+            let mut cluster = first_cluster;
+            while self.volume.is_data_cluster(cluster) && is_lost(self, cluster) {
+                self.owner[cluster as usize] = id;
+                chain.push(cluster);
+                cluster = self.fat[cluster as usize];
+            }
+            self.problem(FsckProblem::LostChain {
+                first_cluster,
+                clusters: chain.len() as u32,
+            });
+            if self.repair {
+                for cluster in chain {
+                    self.owner[cluster as usize] = 0;
+                    self.set_fat(cluster, 0)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_fs_info(&mut self) -> FatResult<()> {
+        let free = (2..=self.volume.max_cluster())
+            .filter(|x| self.fat[*x as usize] == 0)
+            .count() as u32;
+        self.report.free_clusters = free;
+        let bs = self.volume.boot_sector();
+        if self.volume.fat_type() != FatType::Fat32 || bs.fs_info_sector == 0 {
+            return Ok(());
+        }
+        let offset = bs.bytes_from_sector(bs.fs_info_sector as u32);
+        let mut sector = [0u8; 512];
+        self.volume.read_at(offset, &mut sector)?;
+        let u32_at = |pos: usize| u32::from_le_bytes(sector[pos..pos + 4].try_into().unwrap());
+        // a FSInfo sector with bad signatures is ignored by fatfs
+        if u32_at(0) != 0x4161_5252 || u32_at(484) != 0x6141_7272 {
+            return Ok(());
+        }
+        let stored = u32_at(488);
+        if stored == FS_INFO_UNKNOWN || stored == free {
+            return Ok(());
+        }
+        self.problem(FsckProblem::FsInfoFreeCount {
+            stored,
+            actual: free,
+        });
+        if self.repair {
+            self.volume.write_at(offset + 488, &free.to_le_bytes())?;
+            self.report.repaired = true;
+        }
+        Ok(())
+    }
+}
@@ -1,11 +1,19 @@
+use crate::boot_sector::BootSector;
+use crate::cache::BlockCache;
+use crate::disk::{Volume, MAX_FILE_SIZE, ROOT_INO};
+use crate::error::{DeviceError, Errno, FatError};
 use crate::file::{FAT_DENTRY_OPS, FAT_DIR_FILE_OPS};
-use crate::inode::FAT_INODE_DIR_OPS;
-use crate::{FatDir, FatInode, FatInodeType};
+use crate::inode::{update_inode_times, FAT_INODE_DIR_OPS};
+use crate::options::FatMountOptions;
+use crate::partition::find_partition;
+use crate::time::FatClock;
+use crate::{get_fat_sb, FatDir, FatInode, FatInodeType, FatSuperBlock};
 use alloc::boxed::Box;
 use alloc::string::ToString;
 use alloc::sync::{Arc, Weak};
 use core::cmp::min;
 use fatfs::{IoBase, Read, Seek, SeekFrom, Write};
+use log::{debug, error};
 use rvfs::dentry::{DirEntry, DirFlags};
 use rvfs::inode::{Inode, InodeMode};
 use rvfs::mount::MountFlags;
@@ -16,90 +24,177 @@ use rvfs::superblock::{
 use rvfs::{ddebug, StrResult};
 use spin::Mutex;
 
+/// The I/O wrapper of the [Device] for fatfs. All I/O goes through a [BlockCache],
+/// a cloned `FatDevice` has its own position but shares the cache.
+///
+/// The device can be limited to a window of the [Device], e.g. a partition of a disk,
+/// the positions are relative to the start of the window then.
+#[derive(Clone)]
 pub struct FatDevice {
     pos: i64,
-    device_file: Arc<dyn Device>,
+    cache: Arc<BlockCache>,
+    // the byte offset of the window
+    start: u64,
+    // the length of the window, none means the rest of the device
+    len: Option<u64>,
+    read_only: bool,
 }
 impl FatDevice {
+    /// Create a device without cache
     pub fn new(device: Arc<dyn Device>) -> Self {
+        Self::with_cache(Arc::new(BlockCache::passthrough(device)))
+    }
+    pub fn with_cache(cache: Arc<BlockCache>) -> Self {
         Self {
             pos: 0,
-            device_file: device,
+            cache,
+            start: 0,
+            len: None,
+            read_only: false,
+        }
+    }
+    /// Reject every write to the device
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+    /// Limit the I/O to `len` bytes from `start`
+    pub fn with_window(mut self, start: u64, len: u64) -> Self {
+        self.start = start;
+        self.len = Some(len);
+        self.pos = 0;
+        self
+    }
+    pub fn cache(&self) -> &Arc<BlockCache> {
+        &self.cache
+    }
+    /// The byte offset of the window in the device
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+    /// The size of the window
+    pub fn size(&self) -> u64 {
+        let device_size = (self.cache.size() as u64).saturating_sub(self.start);
+        self.len.map_or(device_size, |len| min(len, device_size))
+    }
+    /// Read exactly `buf.len()` bytes at the offset of the window
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(buf)
+    }
+    /// Write the whole `buf` at the offset of the window
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(buf)
+    }
+    /// Read exactly `buf.len()` bytes at the offset of the window past the cache, it is
+    /// for the data of the files
+    pub fn read_direct_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        if offset + buf.len() as u64 > self.size() {
+            return Err(DeviceError::UnexpectedEof);
         }
+        self.cache
+            .read_direct(buf, (self.start + offset) as usize)
+            .map(|_| ())
+    }
+    /// Write the whole `buf` at the offset of the window past the cache
+    pub fn write_direct_at(&self, offset: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        if self.read_only {
+            return Err(DeviceError::ReadOnly);
+        }
+        if offset + buf.len() as u64 > self.size() {
+            return Err(DeviceError::WriteZero);
+        }
+        self.cache
+            .write_direct(buf, (self.start + offset) as usize)
+            .map(|_| ())
+    }
+    // the bytes left in the window from the current position
+    fn remain(&self) -> usize {
+        self.size().saturating_sub(self.pos as u64) as usize
     }
 }
 impl core2::io::Read for FatDevice {
     fn read(&mut self, buf: &mut [u8]) -> core2::io::Result<usize> {
-        let len = self
-            .device_file
-            .read(buf, self.pos as usize)
-            .map_err(|_x| core2::io::Error::new(core2::io::ErrorKind::Other, "other"))?;
-        self.pos += len as i64;
-        Ok(len)
+        Read::read(self, buf).map_err(device_error_to_io)
     }
 }
 impl core2::io::Write for FatDevice {
     fn write(&mut self, buf: &[u8]) -> core2::io::Result<usize> {
-        let len = self
-            .device_file
-            .write(buf, self.pos as usize)
-            .map_err(|_x| core2::io::Error::new(core2::io::ErrorKind::Other, "other"))?;
-        self.pos += len as i64;
-        Ok(len)
+        Write::write(self, buf).map_err(device_error_to_io)
     }
     fn flush(&mut self) -> core2::io::Result<()> {
-        self.device_file.flush();
-        Ok(())
+        Write::flush(self).map_err(device_error_to_io)
     }
 }
 
 impl core2::io::Seek for FatDevice {
     fn seek(&mut self, pos: core2::io::SeekFrom) -> core2::io::Result<u64> {
         let pos = match pos {
-            core2::io::SeekFrom::Start(pos) => pos as i64,
-            core2::io::SeekFrom::End(pos) => self.device_file.size() as i64 + pos,
-            core2::io::SeekFrom::Current(pos) => self.pos + pos,
+            core2::io::SeekFrom::Start(pos) => SeekFrom::Start(pos),
+            core2::io::SeekFrom::End(pos) => SeekFrom::End(pos),
+            core2::io::SeekFrom::Current(pos) => SeekFrom::Current(pos),
         };
-        if pos < 0 {
-            return Err(core2::io::Error::new(
-                core2::io::ErrorKind::Other,
-                "seek error",
-            ));
-        }
-        self.pos = pos;
-        Ok(pos as u64)
+        Seek::seek(self, pos).map_err(device_error_to_io)
     }
 }
 
+fn device_error_to_io(err: DeviceError) -> core2::io::Error {
+    let kind = match err {
+        DeviceError::UnexpectedEof => core2::io::ErrorKind::UnexpectedEof,
+        DeviceError::WriteZero => core2::io::ErrorKind::WriteZero,
+        DeviceError::Seek => core2::io::ErrorKind::InvalidInput,
+        _ => core2::io::ErrorKind::Other,
+    };
+    core2::io::Error::new(kind, "fat device error")
+}
+
 impl IoBase for FatDevice {
-    type Error = ();
+    type Error = DeviceError;
 }
 impl Write for FatDevice {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        core2::io::Write::write(self, buf).map_err(|_| ())
+        if self.read_only {
+            return Err(DeviceError::ReadOnly);
+        }
+        let offset = (self.start + self.pos as u64) as usize;
+        let buf = &buf[..min(buf.len(), self.remain())];
+        let len = self.cache.write(buf, offset)?;
+        self.pos += len as i64;
+        Ok(len)
     }
 
+    // fatfs flushes the device every time it saves the entry of a file, the whole cache
+    // would be written for every write then. The cache is written by sync, fsync and
+    // unmount.
     fn flush(&mut self) -> Result<(), Self::Error> {
-        core2::io::Write::flush(self).map_err(|_| ())
+        Ok(())
     }
 }
 
 impl Read for FatDevice {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        core2::io::Read::read(self, buf).map_err(|_| ())
+        let offset = (self.start + self.pos as u64) as usize;
+        let len = min(buf.len(), self.remain());
+        let buf = &mut buf[..len];
+        let len = self.cache.read(buf, offset)?;
+        self.pos += len as i64;
+        Ok(len)
     }
 }
 
 impl Seek for FatDevice {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
-        let ans = match pos {
-            SeekFrom::Start(pos) => core2::io::Seek::seek(self, core2::io::SeekFrom::Start(pos)),
-            SeekFrom::End(pos) => core2::io::Seek::seek(self, core2::io::SeekFrom::End(pos)),
-            SeekFrom::Current(pos) => {
-                core2::io::Seek::seek(self, core2::io::SeekFrom::Current(pos))
-            }
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(pos) => self.size() as i64 + pos,
+            SeekFrom::Current(pos) => self.pos + pos,
         };
-        ans.map_err(|_| ())
+        if pos < 0 {
+            return Err(DeviceError::Seek);
+        }
+        self.pos = pos;
+        Ok(pos as u64)
     }
 }
 
@@ -126,68 +221,106 @@ fn fat_get_super_blk(
     data: Option<Box<dyn DataOps>>,
 ) -> StrResult<Arc<SuperBlock>> {
     ddebug!("fat get super block");
-    assert!(data.is_some());
-    let device = data.as_ref().unwrap().as_ref().device(dev_name);
-    assert!(device.is_some());
-    let device = device.unwrap();
-    let fat_device = FatDevice::new(device.clone());
-    let fs = fatfs::FileSystem::new(fat_device, fatfs::FsOptions::new()).unwrap();
-    let stats = fs.stats();
-    if stats.is_err() {
-        return Err("read fat data error");
-    }
-    let stats = stats.unwrap();
+    let device = data
+        .as_ref()
+        .ok_or(Errno::EINVAL.as_str())?
+        .device(dev_name)
+        .ok_or(Errno::EINVAL.as_str())?;
+    let mut options = data
+        .as_ref()
+        .map(|data| FatMountOptions::from_data(data.as_ref()))
+        .unwrap_or_default();
+    options.read_only |= flags.contains(MountFlags::MNT_RDONLY);
+    // find the window of the filesystem if it is in a partition
+    let mut raw_device = FatDevice::new(device.clone());
+    if let Some(partition) = find_partition(&mut raw_device, &options.partition)? {
+        debug!("mount partition {:?}", partition);
+        raw_device = raw_device.with_window(partition.start, partition.len);
+    }
+    let window = (raw_device.start(), raw_device.size());
+    // check the boot sector before giving the device to fatfs
+    let boot_sector = BootSector::read_from(raw_device).map_err(|err| {
+        error!("not a fat volume: {}", err);
+        // the checks of the boot sector are reported as a bad superblock
+        Errno::from_message(err).map_or(Errno::EINVAL.as_str(), |_| err)
+    })?;
+    let cache = Arc::new(BlockCache::new(
+        device.clone(),
+        boot_sector.bytes_per_sector as usize,
+        options.cache_blocks,
+        options.cache_policy,
+    ));
+    let fat_device = FatDevice::with_cache(cache.clone())
+        .with_window(window.0, window.1)
+        .with_read_only(options.read_only);
+    let volume =
+        Volume::with_boot_sector(fat_device.clone(), boot_sector)?.with_codepage(options.codepage);
+    // a read only mount must not touch the FSInfo sector
+    let clock = FatClock::new(options.clock.clone(), options.time_offset);
+    let fs_options = fatfs::FsOptions::new()
+        .update_fs_info(!options.read_only)
+        .time_provider(clock.clone())
+        .oem_cp_converter(options.codepage);
+    let fs = fatfs::FileSystem::new(fat_device, fs_options).map_err(FatError::from)?;
+    let stats = fs.stats().map_err(FatError::from)?;
+    let root_dir = fs.root_dir();
+    // the super block owns the filesystem until it is killed
+    let fat_sb = FatSuperBlock::new(fs, cache, volume, options.read_only, clock, data);
     let sb_blk = SuperBlock {
         dev_desc: 777,
         device: Some(device),
         block_size: stats.cluster_size(),
         dirty_flag: false,
-        file_max_bytes: usize::MAX,
+        file_max_bytes: MAX_FILE_SIZE as usize,
         mount_flag: flags,
         magic: 0,
         file_system_type: Arc::downgrade(&fs_type),
         super_block_ops: FATFS_SB_OPS,
         blk_dev_name: dev_name.to_string(),
-        data,
+        data: Some(Box::new(fat_sb)),
         inner: Mutex::new(SuperBlockInner::empty()),
     };
     // set the root dentry for super block
     let sb_blk = Arc::new(sb_blk);
-    let inode = fat_root_inode(sb_blk.clone(), fs.root_dir());
+    let inode = fat_root_inode(sb_blk.clone(), root_dir);
     let dentry = DirEntry::new(DirFlags::empty(), inode, FAT_DENTRY_OPS, Weak::new(), "/");
     sb_blk.update_root(Arc::new(dentry));
     Ok(sb_blk)
 }
 
 fn fat_kill_super_blk(super_blk: Arc<SuperBlock>) {
-    let ops = super_blk.super_block_ops.sync_fs;
-    ops(super_blk).unwrap();
+    let fat_sb = get_fat_sb(&super_blk);
+    // unmount writes the FSInfo back and clears the volume dirty flag
+    if let Err(err) = fat_sb.unmount() {
+        error!("unmount fat failed: {}", err);
+    }
 }
 
 fn fat_sync_fs(sb_blk: Arc<SuperBlock>) -> StrResult<()> {
-    let device = sb_blk.device.as_ref().unwrap().clone();
-    let mut fat_device = FatDevice::new(device);
-    fat_device.flush().unwrap();
+    let fat_sb = get_fat_sb(&sb_blk);
+    fat_sb.sync()?;
     Ok(())
 }
 
 /// create the root inode for fat file system
 fn fat_root_inode(sb_blk: Arc<SuperBlock>, dir: FatDir) -> Arc<Inode> {
-    let _device = sb_blk.device.as_ref().unwrap().clone();
+    let root_cluster = get_fat_sb(&sb_blk).volume.root_cluster();
     let inode = Inode::new(
         sb_blk,
-        0,
+        ROOT_INO,
         0,
         FAT_INODE_DIR_OPS,
         FAT_DIR_FILE_OPS,
         None,
         InodeMode::S_DIR,
     );
-    let parent = Arc::new(Mutex::new(dir));
-    let fat_inode = FatInode::new(parent.clone(), FatInodeType::Dir(parent));
+    let dir = FatInodeType::dir(Some(dir));
+    let fat_inode = FatInode::new(dir, root_cluster, 0);
     inode.access_inner().data = Some(Box::new(fat_inode));
     inode.access_inner().hard_links = 1;
-    Arc::new(inode)
+    let inode = Arc::new(inode);
+    update_inode_times(&inode);
+    inode
 }
 
 fn fat_statfs(super_blk: Arc<SuperBlock>) -> StrResult<StatFs> {
@@ -196,12 +329,20 @@ fn fat_statfs(super_blk: Arc<SuperBlock>) -> StrResult<StatFs> {
     let fs_type = fs_type.name.as_bytes();
     let min = min(fs_type.len(), name.len());
     name[..min].copy_from_slice(&fs_type[..min]);
+    let stats = get_fat_sb(&super_blk).fs.stats();
+    // fatfs uses the FSInfo free cluster count when it is valid and
+    // scans the FAT otherwise, the count is kept up to date on every
+    // cluster allocation and release.
+    let stats = stats.map_err(FatError::from)?;
     Ok(StatFs {
         fs_type: super_blk.magic,
-        block_size: 512,
-        total_blocks: 64 * 1024 * 1024 / 512,
-        free_blocks: 64 * 1024 * 1024 / 512,
-        total_inodes: 999,
+        block_size: stats.cluster_size() as u64,
+        total_blocks: stats.total_clusters() as u64,
+        free_blocks: stats.free_clusters() as u64,
+        // FAT has no inode table, every non-empty file or directory
+        // needs at least one cluster, so the cluster count bounds the
+        // number of files the volume can hold.
+        total_inodes: stats.total_clusters() as u64,
         name_len: 255,
         name,
     })
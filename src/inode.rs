@@ -1,16 +1,20 @@
-use crate::file::{FAT_DIR_FILE_OPS, FAT_FILE_FILE_OPS};
-use crate::{get_fat_data, FatDir, FatInode, FatInodeType};
+use crate::disk::{name_eq, trim_name, DirItem, DosDateTime, Volume, MAX_FILE_SIZE};
+use crate::error::{FatError, FatResult};
+use crate::file::{write_zeros, FAT_DIR_FILE_OPS, FAT_FILE_FILE_OPS};
+use crate::time::Timespec;
+use crate::{check_writable, get_fat_data, get_fat_sb, FatAttr, FatInode, FatInodeType};
 use alloc::boxed::Box;
+use alloc::format;
 use alloc::string::ToString;
 use alloc::sync::Arc;
-use fatfs::{Error, Seek, SeekFrom};
+use fatfs::{Error, Seek, SeekFrom, Write};
 use log::{debug, trace};
 use rvfs::dentry::DirEntry;
 use rvfs::file::{FileMode, FileOps};
-use rvfs::inode::{Inode, InodeMode, InodeOps};
+use rvfs::inode::{Inode, InodeAttr, InodeMode, InodeOps};
+use rvfs::stat::StatTime;
 use rvfs::superblock::SuperBlock;
 use rvfs::{ddebug, StrResult};
-use spin::Mutex;
 
 pub const FAT_INODE_DIR_OPS: InodeOps = {
     let mut ops = InodeOps::empty();
@@ -20,59 +24,170 @@ pub const FAT_INODE_DIR_OPS: InodeOps = {
     ops.rename = fat_rename;
     ops.lookup = fat_lookup;
     ops.unlink = fat_unlink;
+    ops.set_attr = fat_set_attr;
     ops
 };
 
 pub const FAT_INODE_FILE_OPS: InodeOps = {
     let mut ops = InodeOps::empty();
     ops.truncate = fat_truncate;
+    ops.set_attr = fat_set_attr;
     ops
 };
 
 fn fat_truncate(inode: Arc<Inode>) -> StrResult<()> {
+    // the vfs has set the new size in the inode
+    let size = inode.access_inner().file_size as u64;
+    fat_truncate_to(&inode, size)?;
+    Ok(())
+}
+
+/// Description:
+///
+/// Set the size of a file, the new bytes of a larger size are zeros. It is the truncate
+/// of the vfs, and it can be called for an open file (ftruncate) too. A size after
+/// [MAX_FILE_SIZE] is EFBIG, the inode keeps the size of the file on disk then.
+pub fn fat_truncate_to(inode: &Arc<Inode>, size: u64) -> FatResult<()> {
+    check_writable(inode)?;
+    let sb_blk = inode.super_blk.upgrade().unwrap();
+    let fat_sb = get_fat_sb(&sb_blk);
+    let volume = &fat_sb.volume;
     let fat_data = get_fat_data(inode.clone());
-    let inode_inner = inode.access_inner();
-    let file_size = inode_inner.file_size;
-    let parent = &fat_data.parent;
+    let file = fat_data.fat_file(volume)?;
+    let parent = fat_data.parent_dir(volume)?;
     let _parent = parent.lock();
-    if let FatInodeType::File((_name, file)) = &fat_data.current {
-        let mut file = file.as_ref().unwrap().lock();
-        let res = file.seek(fatfs::SeekFrom::Start(file_size as u64));
-        if res.is_err() {
-            return Err("Seek file failed");
-        }
-        let res = file.truncate();
-        if res.is_err() {
-            return Err("Truncate file failed");
-        }
+    let mut file = file.lock();
+    let old_size = volume.read_dir_entry(fat_data.offset)?.size() as u64;
+    let res = if size > MAX_FILE_SIZE {
+        Err(FatError::FileTooBig)
+    } else if size > old_size {
+        write_zeros(&mut file, old_size, size)
     } else {
-        return Err("Not a file");
+        file.seek(SeekFrom::Start(size))
+            .and_then(|_| file.truncate())
+            .map_err(FatError::from)
+    };
+    // the readers find the size in the entry on disk, it is right after a failure too
+    file.flush()?;
+    fat_data.invalidate_extents();
+    let new_size = volume.read_dir_entry(fat_data.offset)?.size() as u64;
+    inode.access_inner().file_size = new_size as usize;
+    res?;
+    fat_data.data_changed(new_size, fat_sb);
+    update_inode_times(inode);
+    Ok(())
+}
+
+/// The attributes of an inode as `stat` shows them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FatStat {
+    pub ino: usize,
+    pub size: u64,
+    /// the 512 byte blocks of the clusters of the file
+    pub blocks: u64,
+    pub atime: Timespec,
+    pub mtime: Timespec,
+    pub ctime: Timespec,
+    /// the creation time
+    pub btime: Timespec,
+}
+
+/// Description:
+///
+/// The attributes of an inode, the times come from its entry and the changes that have
+/// not been written back yet. They are in local time on disk, see
+/// [crate::time::FatClock]. FAT keeps only the day of the last access, and the root has
+/// no entry, its times are all 1980-01-01.
+pub fn fat_getattr(inode: &Arc<Inode>) -> FatStat {
+    let sb_blk = inode.super_blk.upgrade().unwrap();
+    let clock = &get_fat_sb(&sb_blk).clock;
+    let size = inode.access_inner().file_size as u64;
+    let fat_data = get_fat_data(inode.clone());
+    let attr = *fat_data.attr.lock();
+    FatStat {
+        ino: inode.number,
+        size,
+        blocks: attr.blocks,
+        atime: clock.to_timespec(attr.atime, 0),
+        mtime: clock.to_timespec(attr.mtime, 0),
+        ctime: clock.to_timespec(attr.ctime, 0),
+        btime: clock.to_timespec(attr.crtime.0, attr.crtime.1),
     }
+}
+
+/// Copy the times of [fat_getattr] to the vfs inode, they change with the attributes
+pub(crate) fn update_inode_times(inode: &Arc<Inode>) {
+    let stat = fat_getattr(inode);
+    let stat_time = |time: Timespec| StatTime {
+        tv_sec: time.sec.max(0) as u64,
+        tv_nsec: time.nsec as u64,
+    };
+    let mut inner = inode.access_inner();
+    inner.atime = stat_time(stat.atime);
+    inner.mtime = stat_time(stat.mtime);
+    inner.ctime = stat_time(stat.ctime);
+    inner.btime = stat_time(stat.btime);
+}
+
+/// `utimensat` through the vfs, a time that is not given is kept
+fn fat_set_attr(dentry: Arc<DirEntry>, attr: InodeAttr) -> StrResult<()> {
+    let inode = dentry.access_inner().d_inode.clone();
+    let timespec = |time: StatTime| Timespec::new(time.tv_sec as i64, time.tv_nsec as u32);
+    fat_set_times(&inode, attr.atime.map(timespec), attr.mtime.map(timespec))?;
+    Ok(())
+}
+
+/// Description:
+///
+/// Set the access and the modification time of an inode, `None` keeps a time as it is
+/// (UTIME_OMIT). The times are rounded down to what the entry can store, the day of the
+/// access and 2 seconds of the modification. They are written to the entry with the
+/// other attributes by flush, sync or unmount.
+pub fn fat_set_times(
+    inode: &Arc<Inode>,
+    atime: Option<Timespec>,
+    mtime: Option<Timespec>,
+) -> FatResult<()> {
+    check_writable(inode)?;
+    let sb_blk = inode.super_blk.upgrade().unwrap();
+    let clock = &get_fat_sb(&sb_blk).clock;
+    let fat_data = get_fat_data(inode.clone());
+    let mut attr = fat_data.attr.lock();
+    if let Some(atime) = atime {
+        let (atime, _) = clock.to_dos(atime);
+        attr.atime = DosDateTime {
+            date: atime.date,
+            time: 0,
+        };
+    }
+    if let Some(mtime) = mtime {
+        attr.mtime = clock.to_dos(mtime).0;
+    }
+    attr.ctime = clock.now().0;
+    attr.dirty = true;
+    drop(attr);
+    update_inode_times(inode);
     Ok(())
 }
 
 fn fat_mkdir(dir: Arc<Inode>, dentry: Arc<DirEntry>, _mode: FileMode) -> StrResult<()> {
     ddebug!("fat_mkdir");
+    check_writable(&dir)?;
     let fat_data = get_fat_data(dir.clone());
-    let name = dentry.access_inner().d_name.clone();
-    let res = __fat_create_dir_or_file(fat_data, true, &name);
-    let (parent_dir, current) = match res {
-        Ok((dir, cur)) => (dir, cur),
-        Err(Error::InvalidInput) => return Err("File exist"),
-        Err(Error::NotEnoughSpace) => return Err("No space"),
-        Err(Error::Io(_)) => return Err("IO error"),
-        _ => return Err("Unknown error"),
-    };
+    let name = trim_name(&dentry.access_inner().d_name).to_string();
     let sb_blk = dir.super_blk.upgrade().unwrap();
+    let volume = &get_fat_sb(&sb_blk).volume;
+    let (item, current) = __fat_create_dir_or_file(fat_data, volume, true, &name)?;
     // create a inode for the dentry
     let inode = generate_fat_inode(
-        sb_blk,
+        &dir,
+        &item,
         FAT_INODE_DIR_OPS,
         FAT_DIR_FILE_OPS,
         InodeMode::S_DIR,
-        parent_dir,
         current,
     );
+    get_fat_sb(&sb_blk).insert_inode(item.offset, &inode);
     // set the dentry's inode
     dentry.access_inner().d_inode = inode;
     ddebug!("fat_mkdir end");
@@ -80,55 +195,51 @@ fn fat_mkdir(dir: Arc<Inode>, dentry: Arc<DirEntry>, _mode: FileMode) -> StrResu
 }
 
 fn fat_rmdir(dir: Arc<Inode>, dentry: Arc<DirEntry>) -> StrResult<()> {
-    let fat_data = get_fat_data(dir);
-    let name = dentry.access_inner().d_name.clone();
-    let res = __fat_remove_dir_or_file(fat_data, &name);
-    match res {
-        Ok(_) => {}
-        Err(Error::InvalidInput) => return Err("File not exist"),
-        Err(Error::NotEnoughSpace) => return Err("No space"),
-        Err(Error::Io(_)) => return Err("IO error"),
-        _ => return Err("Unknown error"),
-    };
+    check_writable(&dir)?;
+    let sub_data = get_fat_data(dentry.access_inner().d_inode.clone());
+    if !matches!(sub_data.current, FatInodeType::Dir(_)) {
+        return Err(FatError::NotDir.into());
+    }
+    let offset = sub_data.offset;
+    let sb_blk = dir.super_blk.upgrade().unwrap();
+    let volume = &get_fat_sb(&sb_blk).volume;
+    let name = sub_data.fatfs_name(volume)?;
+    __fat_remove_dir_or_file(get_fat_data(dir), volume, &name)?;
+    get_fat_sb(&sb_blk).remove_inode(offset);
     Ok(())
 }
 
 fn fat_unlink(dir: Arc<Inode>, dentry: Arc<DirEntry>) -> StrResult<()> {
-    let file_data = get_fat_data(dentry.access_inner().d_inode.clone());
-    delete_file(file_data).unwrap();
-    let fat_data = get_fat_data(dir.clone());
-    let name = dentry.access_inner().d_name.clone();
-    let res = __fat_remove_dir_or_file(fat_data, &name);
-    match res {
-        Ok(_) => {}
-        Err(Error::InvalidInput) => return Err("File not exist"),
-        Err(Error::NotEnoughSpace) => return Err("No space"),
-        Err(Error::Io(_)) => return Err("IO error"),
-        _ => return Err("Unknown error"),
-    };
+    check_writable(&dir)?;
+    let inode = dentry.access_inner().d_inode.clone();
+    let sb_blk = dir.super_blk.upgrade().unwrap();
+    let volume = &get_fat_sb(&sb_blk).volume;
+    // fatfs finds the file by its own name, see DirItem::fatfs_name
+    let name = get_fat_data(inode.clone()).fatfs_name(volume)?;
+    delete_file(&inode)?;
+    let offset = get_fat_data(inode).offset;
+    __fat_remove_dir_or_file(get_fat_data(dir.clone()), volume, &name)?;
+    get_fat_sb(&sb_blk).remove_inode(offset);
     Ok(())
 }
 
 fn fat_create(dir: Arc<Inode>, dentry: Arc<DirEntry>, _mode: FileMode) -> StrResult<()> {
+    check_writable(&dir)?;
     let fat_data = get_fat_data(dir.clone());
-    let name = dentry.access_inner().d_name.clone();
-    let res = __fat_create_dir_or_file(fat_data, false, &name);
-    let (parent, current) = match res {
-        Ok((dir, file)) => (dir, file),
-        Err(Error::NotEnoughSpace) => return Err("No space"),
-        Err(Error::Io(_)) => return Err("IO error"),
-        _ => return Err("Unknown error"),
-    };
+    let name = trim_name(&dentry.access_inner().d_name).to_string();
     let sb_blk = dir.super_blk.upgrade().unwrap();
+    let volume = &get_fat_sb(&sb_blk).volume;
+    let (item, current) = __fat_create_dir_or_file(fat_data, volume, false, &name)?;
     // create a inode for the dentry
     let inode = generate_fat_inode(
-        sb_blk,
+        &dir,
+        &item,
         FAT_INODE_FILE_OPS,
         FAT_FILE_FILE_OPS,
         InodeMode::S_FILE,
-        parent,
         current,
     );
+    get_fat_sb(&sb_blk).insert_inode(item.offset, &inode);
     // set the dentry's inode
     dentry.access_inner().d_inode = inode;
     Ok(())
@@ -140,216 +251,278 @@ fn fat_rename(
     new_dir: Arc<Inode>,
     new_dentry: Arc<DirEntry>,
 ) -> StrResult<()> {
-    let old_name = old_dentry.access_inner().d_name.clone();
-    let new_name = new_dentry.access_inner().d_name.clone();
+    check_writable(&dir)?;
+    let old_name = trim_name(&old_dentry.access_inner().d_name).to_string();
+    let new_name = trim_name(&new_dentry.access_inner().d_name).to_string();
     // whether the dir is equal to the new_dir
     let is_same_dir = Arc::ptr_eq(&dir, &new_dir);
+    let sb_blk = dir.super_blk.upgrade().unwrap();
+    let fat_sb = get_fat_sb(&sb_blk);
+    let new_dir_cluster = get_fat_data(new_dir.clone()).cluster;
+    let new_dir_inode = new_dir.clone();
+    if is_same_dir && name_eq(&old_name, &new_name) {
+        let inode = old_dentry.access_inner().d_inode.clone();
+        rename_case(&dir, &inode, &old_name, &new_name)?;
+        return Ok(());
+    }
+    let volume = &fat_sb.volume;
+    // fatfs finds the file by its own name, see DirItem::fatfs_name
+    let old_fatfs_name =
+        get_fat_data(old_dentry.access_inner().d_inode.clone()).fatfs_name(volume)?;
+    // the replaced file is removed first, its inode must not be found at the old
+    // position. fatfs also finds a name whose upper case is longer, like STRASSE for
+    // straße. That file is not the target for vfat, fatfs fails with EEXIST then.
+    let target = volume.find(new_dir_cluster, &new_name)?;
     let old_fat_data = get_fat_data(dir);
-    if is_same_dir {
-        // rename in the same dir
-        if let FatInodeType::Dir(dir) = &old_fat_data.current {
-            let dir = dir.lock();
-            let res = dir.rename(&old_name, &(*dir), &new_name);
-            match res {
-                Ok(_) => {}
-                Err(Error::AlreadyExists) => {
-                    // try delete the target src
-                    let res = dir.remove(&new_name);
-                    if res.is_err() {
-                        return Err("fat error");
-                    }
-                    dir.rename(&old_name, &(*dir), &new_name).unwrap();
-                }
-                _ => return Err("fat error"),
-            }
-            let old_fat_file_data = get_fat_data(old_dentry.access_inner().d_inode.clone());
-
-            if let FatInodeType::File((_name, Some(file))) = &old_fat_file_data.current {
-                old_fat_file_data.current = FatInodeType::File((new_name, Some(file.clone())))
-            }
+    let new_fat_data = get_fat_data(new_dir);
+    let old_dir = old_fat_data.fat_dir(volume)?;
+    let new_dir = new_fat_data.fat_dir(volume)?;
+    {
+        let old_dir = old_dir.lock();
+        // the directory is locked once if it is both
+        let new_dir_lock;
+        let new_dir = if is_same_dir {
+            &*old_dir
         } else {
-            return Err("It is not a dir");
+            new_dir_lock = new_dir.lock();
+            &*new_dir_lock
+        };
+        if let Some(target) = target {
+            new_dir
+                .remove(&target.fatfs_name(&volume.codepage()))
+                .map_err(FatError::from)?;
+            fat_sb.remove_inode(target.offset);
         }
-    } else {
-        let new_fat_data = get_fat_data(new_dir);
-        if let FatInodeType::Dir(old_dir) = &old_fat_data.current
-            && let FatInodeType::Dir(new_dir) = &new_fat_data.current {
-            let old_dir = old_dir.lock();
-            let new_dir = new_dir.lock();
-            let res = old_dir.rename(&old_name, &(*new_dir), &new_name);
-            match res {
-                Ok(_) => {}
-                Err(Error::AlreadyExists) => {
-                    // try delete the target src
-                    let res = new_dir.remove(&new_name);
-                    if res.is_err(){
-                        return Err("fat error")
-                    }
-                    old_dir.rename(&old_name,&(*new_dir),&new_name).unwrap();
-                }
-                _ => return Err("fat error")
-            }
-            let old_fat_file_data = get_fat_data(old_dentry.access_inner().d_inode.clone());
-            if let FatInodeType::File((_name,Some(file))) = &old_fat_file_data.current {
-                old_fat_file_data.current = FatInodeType::File((new_name, Some(file.clone())))
-            }
-
-            old_fat_file_data.parent = new_fat_data.parent.clone();
-        }else {
-            return Err("It is not a dir")
+        old_dir
+            .rename(&old_fatfs_name, new_dir, &new_name)
+            .map_err(FatError::from)?;
+    }
+    if !is_same_dir {
+        let old_fat_file_data = get_fat_data(old_dentry.access_inner().d_inode.clone());
+        old_fat_file_data.parent_inode = Some(new_dir_inode);
+        // fatfs keeps the `..` of a moved directory
+        if matches!(old_fat_file_data.current, FatInodeType::Dir(_)) {
+            let parent_cluster = if new_fat_data.offset == 0 {
+                0
+            } else {
+                new_dir_cluster
+            };
+            fat_sb
+                .volume
+                .set_dot_dot(old_fat_file_data.cluster, parent_cluster)?;
         }
     }
-    //todo
+    // the entry has moved, the inode follows it
+    let old_fat_file_data = get_fat_data(old_dentry.access_inner().d_inode.clone());
+    let item = find_item(&sb_blk, new_dir_cluster, &new_name)?;
+    fat_sb.volume.set_oem_short_name(new_dir_cluster, &item)?;
+    fat_sb.move_inode(old_fat_file_data.offset, item.offset);
+    old_fat_file_data.offset = item.offset;
+    old_fat_file_data.moved();
+    Ok(())
+}
+
+/// Description:
+///
+/// Rename a file to another case of its name. fatfs would find the file itself as the
+/// target, so the long name is rewritten in place. If that can not be done, the file is
+/// renamed to a free temporary name first.
+fn rename_case(
+    dir: &Arc<Inode>,
+    inode: &Arc<Inode>,
+    old_name: &str,
+    new_name: &str,
+) -> FatResult<()> {
+    if old_name == new_name {
+        return Ok(());
+    }
+    let sb_blk = dir.super_blk.upgrade().unwrap();
+    let fat_sb = get_fat_sb(&sb_blk);
+    let dir_data = get_fat_data(dir.clone());
+    let fat_dir = dir_data.fat_dir(&fat_sb.volume)?;
+    let fat_dir = fat_dir.lock();
+    let item = find_item(&sb_blk, dir_data.cluster, old_name)?;
+    let renamed = !fat_sb.volume.set_long_name(&item, new_name)?;
+    if renamed {
+        let mut i = 0;
+        let tmp = loop {
+            let tmp = format!("rename~{}.tmp", i);
+            if fat_sb.volume.find(dir_data.cluster, &tmp)?.is_none() {
+                break tmp;
+            }
+            i += 1;
+        };
+        let old_name = item.fatfs_name(&fat_sb.volume.codepage());
+        fat_dir.rename(&old_name, &fat_dir, &tmp)?;
+        fat_dir.rename(&tmp, &fat_dir, new_name)?;
+    }
+    if !renamed {
+        return Ok(());
+    }
+    // the handle of the file is closed below, the directory must not be locked then
+    drop(fat_dir);
+    let file_data = get_fat_data(inode.clone());
+    let item = find_item(&sb_blk, dir_data.cluster, new_name)?;
+    // the entries are new even if they took the old place
+    fat_sb.volume.set_oem_short_name(dir_data.cluster, &item)?;
+    fat_sb.move_inode(file_data.offset, item.offset);
+    file_data.offset = item.offset;
+    file_data.moved();
     Ok(())
 }
 
 fn fat_lookup(p_dir: Arc<Inode>, dentry: Arc<DirEntry>) -> StrResult<()> {
     ddebug!("fat_lookup start");
     let fat_data = get_fat_data(p_dir.clone());
-    let name = dentry.access_inner().d_name.clone();
-    let current = &fat_data.current;
-    if let FatInodeType::Dir(c_dir) = current {
-        let dir = c_dir.lock();
-        let res = dir.open_dir(&name);
-        let res2 = dir.open_file(&name);
-        drop(dir);
-        if res.is_err() && res2.is_err() {
-            return Err("File not exist");
-        }
-        let sb_blk = p_dir.super_blk.upgrade().unwrap();
-        if res.is_ok() {
-            let dir = res.unwrap();
-            let mut count = 0;
-            dir.iter().for_each(|x| {
-                if x.is_ok() {
-                    count += 1;
-                }
-            });
-            let current = FatInodeType::Dir(Arc::new(Mutex::new(dir)));
-            let inode = generate_fat_inode(
-                sb_blk,
+    let name = trim_name(&dentry.access_inner().d_name).to_string();
+    if !matches!(fat_data.current, FatInodeType::Dir(_)) {
+        return Err(FatError::NotDir.into());
+    }
+    // the root has no `.` and `..` on disk, they are the same for every directory
+    if name == "." || name == ".." {
+        let inode = match (name.as_str(), &fat_data.parent_inode) {
+            ("..", Some(parent)) => parent.clone(),
+            _ => p_dir.clone(),
+        };
+        dentry.access_inner().d_inode = inode;
+        return Ok(());
+    }
+    let sb_blk = p_dir.super_blk.upgrade().unwrap();
+    // the entry on disk gives the type, the size and the inode number
+    let item = find_item(&sb_blk, fat_data.cluster, &name)?;
+    // an inode that is still alive is shared, so is its fatfs handle. A new one is built
+    // from the entry, fatfs opens it when it is written.
+    let inode = get_fat_sb(&sb_blk).get_or_insert_inode(item.offset, || {
+        let inode = if item.entry.is_dir() {
+            generate_fat_inode(
+                &p_dir,
+                &item,
                 FAT_INODE_DIR_OPS,
                 FAT_DIR_FILE_OPS,
                 InodeMode::S_DIR,
-                c_dir.clone(),
-                current,
-            );
-            // set the dir size with sub file numer
-            inode.access_inner().file_size = count;
-            dentry.access_inner().d_inode = inode;
-        } else if res2.is_ok() {
-            let file = res2.unwrap();
-            let current = FatInodeType::File((name.clone(), Some(Arc::new(Mutex::new(file)))));
+                FatInodeType::dir(None),
+            )
+        } else {
             let inode = generate_fat_inode(
-                sb_blk,
+                &p_dir,
+                &item,
                 FAT_INODE_FILE_OPS,
                 FAT_FILE_FILE_OPS,
                 InodeMode::S_FILE,
-                c_dir.clone(),
-                current,
+                FatInodeType::file(None),
             );
-            // set the file size
-            let parent_dir = c_dir.lock();
-            parent_dir.iter().for_each(|x| {
-                if x.is_ok() {
-                    let x = x.unwrap();
-                    if x.file_name() == name {
-                        debug!("set file size:{}", x.len());
-                        inode.access_inner().file_size = x.len() as usize;
-                        return;
-                    }
-                }
-            });
-            dentry.access_inner().d_inode = inode;
-        }
-    } else {
-        return Err("It is not a dir");
-    }
+            debug!("set file size:{}", item.entry.size());
+            inode.access_inner().file_size = item.entry.size() as usize;
+            inode
+        };
+        Ok(inode)
+    })?;
+    dentry.access_inner().d_inode = inode;
     ddebug!("fat_lookup end");
     Ok(())
 }
 
+/// Find the entry of a file on disk, `dir_cluster` is the cluster of its directory
+fn find_item(sb_blk: &SuperBlock, dir_cluster: u32, name: &str) -> FatResult<DirItem> {
+    get_fat_sb(sb_blk)
+        .volume
+        .find(dir_cluster, name)?
+        .ok_or(FatError::from(Error::NotFound))
+}
+
 /// user should set the file size in the inode after calling this function
 fn generate_fat_inode(
-    sb_blk: Arc<SuperBlock>,
+    dir: &Arc<Inode>,
+    item: &DirItem,
     inode_ops: InodeOps,
     file_ops: FileOps,
     mode: InodeMode,
-    parent: Arc<Mutex<FatDir>>,
     current: FatInodeType,
 ) -> Arc<Inode> {
-    let inode = Inode::new(sb_blk, 0, 0, inode_ops, file_ops, None, mode);
+    let sb_blk = dir.super_blk.upgrade().unwrap();
+    let fat_sb = get_fat_sb(&sb_blk);
+    let volume = &fat_sb.volume;
+    let number = fat_sb.ino(item.offset);
+    let attr = FatAttr::new(&item.entry, volume.cluster_size());
+    let cluster = if item.entry.is_dir() {
+        item.entry.first_cluster()
+    } else {
+        0
+    };
+    let inode = Inode::new(sb_blk, number, 0, inode_ops, file_ops, None, mode);
     // add fat data
-    let fat_data = FatInode::new(parent, current);
+    let mut fat_data = FatInode::new(current, cluster, item.offset);
+    *fat_data.attr.lock() = attr;
+    fat_data.parent_inode = Some(dir.clone());
     let fat_data = Box::new(fat_data);
     inode.access_inner().data = Some(fat_data);
     inode.access_inner().hard_links = 1;
-    Arc::new(inode)
+    let inode = Arc::new(inode);
+    update_inode_times(&inode);
+    inode
 }
+
+/// Create the file or directory with fatfs, its item and the inode type are returned
 fn __fat_create_dir_or_file(
     fat_data: &mut FatInode,
+    volume: &Volume,
     is_dir: bool,
     name: &str,
-) -> Result<(Arc<Mutex<FatDir>>, FatInodeType), Error<()>> {
+) -> FatResult<(DirItem, FatInodeType)> {
     ddebug!("create dir or file");
     debug!("name: {}", name);
-    let current = &fat_data.current;
-    return match current {
-        FatInodeType::Dir(dir) => {
-            let dir_lock = dir.lock();
-            if is_dir {
-                let new_dir = dir_lock.create_dir(name)?;
-                Ok((
-                    dir.clone(),
-                    FatInodeType::Dir(Arc::new(Mutex::new(new_dir))),
-                ))
-            } else {
-                let file = dir_lock.create_file(name)?;
-                Ok((
-                    dir.clone(),
-                    FatInodeType::File((name.to_string(), Some(Arc::new(Mutex::new(file))))),
-                ))
-            }
-        }
-        _ => Err(Error::InvalidInput),
+    let dir = fat_data.fat_dir(volume)?;
+    let dir_lock = dir.lock();
+    // fatfs opens a file whose name only differs in case, or is its short name
+    if volume.find(fat_data.cluster, name)?.is_some() {
+        return Err(Error::AlreadyExists.into());
+    }
+    let current = if is_dir {
+        FatInodeType::dir(Some(dir_lock.create_dir(name)?))
+    } else {
+        FatInodeType::file(Some(dir_lock.create_file(name)?))
     };
+    // fatfs also finds a name whose upper case is longer, like STRASSE for straße, and
+    // opens that file. It is another name for vfat, but it can not be created.
+    let item = volume
+        .find(fat_data.cluster, name)?
+        .ok_or(FatError::from(Error::AlreadyExists))?;
+    if name.is_ascii() {
+        return Ok((item, current));
+    }
+    // the short name gets the characters of the code page. The handle would write back
+    // the short name of fatfs, it is opened again when it is needed.
+    drop(current);
+    volume.set_oem_short_name(fat_data.cluster, &item)?;
+    if is_dir {
+        Ok((item, FatInodeType::dir(None)))
+    } else {
+        Ok((item, FatInodeType::file(None)))
+    }
 }
 
-fn __fat_remove_dir_or_file(fat_data: &mut FatInode, name: &str) -> Result<(), Error<()>> {
-    let current = &fat_data.current;
-    match current {
-        FatInodeType::Dir(dir) => {
-            let dir_lock = dir.lock();
-            trace!("remove dir or file");
-            dir_lock.remove(name)?;
-            let fs = dir_lock.get_fs();
-            fs.unmount().unwrap();
-            trace!("remove dir or file end fs_ptr:{:p}", fs);
-        }
-        _ => {
-            return Err(Error::InvalidInput);
-        }
-    };
+fn __fat_remove_dir_or_file(fat_data: &mut FatInode, volume: &Volume, name: &str) -> FatResult<()> {
+    let dir = fat_data.fat_dir(volume)?;
+    let dir_lock = dir.lock();
+    trace!("remove dir or file");
+    dir_lock.remove(name)?;
+    trace!("remove dir or file end");
     Ok(())
 }
 
-fn delete_file(fat_data: &mut FatInode) -> Result<(), Error<()>> {
-    let current = &fat_data.current;
-    match current {
-        FatInodeType::File((name, file)) => {
-            trace!("truncate file {} to 0", name);
-            if file.is_none() {
-                return Err(Error::InvalidInput);
-            }
-            let mut file = file.as_ref().unwrap().lock();
-            file.seek(SeekFrom::Start(0)).unwrap();
-            file.truncate().unwrap();
-            let fs = file.get_fs();
-            trace!("truncate file end fs_ptr:{:p}", fs);
-        }
-        _ => {
-            return Err(Error::InvalidInput);
-        }
-    }
+fn delete_file(inode: &Arc<Inode>) -> FatResult<()> {
+    let sb_blk = inode.super_blk.upgrade().unwrap();
+    let fat_data = get_fat_data(inode.clone());
+    let file = fat_data.fat_file(&get_fat_sb(&sb_blk).volume)?;
+    let mut file = file.lock();
+    trace!("truncate file to 0");
+    file.seek(SeekFrom::Start(0))?;
+    file.truncate()?;
+    fat_data.invalidate_extents();
+    drop(file);
+    inode.access_inner().file_size = 0;
+    fat_data.data_changed(0, get_fat_sb(&sb_blk));
+    // the entry is removed next, nothing must be written to it any more
+    fat_data.attr.lock().dirty = false;
     Ok(())
 }
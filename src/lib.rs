@@ -2,21 +2,235 @@
 #![no_std]
 extern crate alloc;
 
+use crate::cache::BlockCache;
+use crate::codepage::CodePage;
+use crate::disk::{DosDateTime, RawDirEntry, Volume};
+use crate::error::{FatError, FatResult};
+use crate::extent::ExtentMap;
 use crate::fstype::FatDevice;
+use crate::time::FatClock;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::string::String;
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
 use core::fmt::{Debug, Formatter};
-use fatfs::{DefaultTimeProvider, Dir, File, LossyOemCpConverter};
+use fatfs::{Dir, DirEntry, File, FileSystem};
 use rvfs::inode::Inode;
-use rvfs::superblock::{DataOps, Device};
+use rvfs::superblock::{DataOps, Device, SuperBlock};
 use spin::Mutex;
 
+pub mod boot_sector;
+pub mod cache;
+pub mod codepage;
+pub mod disk;
+pub mod error;
+pub mod extent;
 pub mod file;
+pub mod format;
+pub mod fsck;
 pub mod fstype;
 pub mod inode;
+pub mod options;
+pub mod partition;
+pub mod time;
+
+type FatFs = FileSystem<FatDevice, FatClock, CodePage>;
+type FatDir = Dir<FatDevice, FatClock, CodePage>;
+type FatFile = File<FatDevice, FatClock, CodePage>;
+type FatDirEntry = DirEntry<FatDevice, FatClock, CodePage>;
 
-type FatDir = Dir<FatDevice, DefaultTimeProvider, LossyOemCpConverter>;
-type FatFile = File<FatDevice, DefaultTimeProvider, LossyOemCpConverter>;
+/// Description:
+///
+/// The fat filesystem that belongs to a super block. It is saved in the `data` field of the
+/// super block, so every inode can reach the same `fatfs::FileSystem` through its super block.
+/// The mount data given by the user is kept here too, so the device can still be found.
+pub struct FatSuperBlock {
+    pub fs: Arc<FatFs>,
+    // the block cache under the fatfs
+    pub cache: Arc<BlockCache>,
+    // the on-disk structures, they are read through the same cache as fatfs
+    pub volume: Volume,
+    // nothing is written to the device if it is true
+    pub read_only: bool,
+    // the time source, fatfs has a copy of it
+    pub clock: FatClock,
+    // the data passed to mount
+    pub mount_data: Option<Box<dyn DataOps>>,
+    // whether the fatfs has been unmounted
+    unmounted: Mutex<bool>,
+    // the live inodes by the position of their entries
+    inodes: Mutex<BTreeMap<u64, Weak<Inode>>>,
+    // the inode numbers of the entries that have moved
+    inos: Mutex<InoTable>,
+}
+
+/// The inode numbers that are not the position of their entries, see [FatSuperBlock::ino]
+struct InoTable {
+    moved: BTreeMap<u64, usize>,
+    // the last number given to a position that lost its own one, they are after the
+    // numbers of the positions
+    last: usize,
+}
+
+impl FatSuperBlock {
+    pub fn new(
+        fs: Arc<FatFs>,
+        cache: Arc<BlockCache>,
+        volume: Volume,
+        read_only: bool,
+        clock: FatClock,
+        mount_data: Option<Box<dyn DataOps>>,
+    ) -> Self {
+        let inos = InoTable {
+            moved: BTreeMap::new(),
+            last: volume.ino(cache.size() as u64),
+        };
+        Self {
+            fs,
+            cache,
+            volume,
+            read_only,
+            clock,
+            mount_data,
+            unmounted: Mutex::new(false),
+            inodes: Mutex::new(BTreeMap::new()),
+            inos: Mutex::new(inos),
+        }
+    }
+    /// Flush the FAT, FSInfo and the block cache without unmounting the filesystem
+    pub fn sync(&self) -> FatResult<()> {
+        if self.read_only || *self.unmounted.lock() {
+            return Ok(());
+        }
+        self.write_back_inodes()?;
+        self.fs.flush()?;
+        self.cache.flush()?;
+        Ok(())
+    }
+    // save the changed attributes of the live inodes in their entries
+    fn write_back_inodes(&self) -> FatResult<()> {
+        let inodes = self.inodes.lock();
+        for inode in inodes.values().filter_map(Weak::upgrade) {
+            get_fat_data(inode).write_back(&self.volume)?;
+        }
+        Ok(())
+    }
+    /// Return an error if the filesystem can not be modified
+    pub fn check_writable(&self) -> FatResult<()> {
+        if self.read_only {
+            return Err(FatError::ReadOnly);
+        }
+        Ok(())
+    }
+    /// Description:
+    ///
+    /// Return the live inode of the entry at `offset`, or create it with `f`. Only weak
+    /// references are kept, an inode goes away with the last dentry that holds it.
+    pub fn get_or_insert_inode<F>(&self, offset: u64, f: F) -> FatResult<Arc<Inode>>
+    where
+        F: FnOnce() -> FatResult<Arc<Inode>>,
+    {
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&offset).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        let inode = f()?;
+        Self::insert(&mut inodes, offset, &inode);
+        Ok(inode)
+    }
+    /// Save a new inode, e.g. the inode of a created file
+    pub fn insert_inode(&self, offset: u64, inode: &Arc<Inode>) {
+        Self::insert(&mut self.inodes.lock(), offset, inode);
+    }
+    fn insert(inodes: &mut BTreeMap<u64, Weak<Inode>>, offset: u64, inode: &Arc<Inode>) {
+        // drop the inodes that are gone
+        inodes.retain(|_, x| x.strong_count() > 0);
+        inodes.insert(offset, Arc::downgrade(inode));
+    }
+    /// Forget the inode of a removed entry. If the inode is still open, its number is not
+    /// given to the next entry at the position.
+    pub fn remove_inode(&self, offset: u64) {
+        let inode = self.inodes.lock().remove(&offset);
+        if inode.is_some_and(|x| x.strong_count() > 0) {
+            self.renumber(offset);
+        }
+    }
+    /// Description:
+    ///
+    /// The entry has moved, e.g. by a rename. The inode and its number follow it, the old
+    /// position gets a new number, so a file created there does not get the same one.
+    pub fn move_inode(&self, old: u64, new: u64) {
+        if old == new {
+            return;
+        }
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.remove(&old) {
+            inodes.insert(new, inode);
+        }
+        let mut inos = self.inos.lock();
+        let number = inos
+            .moved
+            .remove(&old)
+            .unwrap_or_else(|| self.volume.ino(old));
+        if number == self.volume.ino(new) {
+            inos.moved.remove(&new);
+        } else {
+            inos.moved.insert(new, number);
+        }
+        drop(inos);
+        self.renumber(old);
+    }
+    // give the position a number that no entry had
+    fn renumber(&self, offset: u64) {
+        let mut inos = self.inos.lock();
+        inos.last += 1;
+        let last = inos.last;
+        inos.moved.insert(offset, last);
+    }
+    /// Description:
+    ///
+    /// The inode number of the entry at `offset`. It is [Volume::ino] until an entry moves
+    /// there, it is the same for the inode, a lookup and readdir.
+    pub fn ino(&self, offset: u64) -> usize {
+        let inos = self.inos.lock();
+        inos.moved
+            .get(&offset)
+            .copied()
+            .unwrap_or_else(|| self.volume.ino(offset))
+    }
+    /// Unmount the filesystem. It only happens once, the later calls do nothing.
+    pub fn unmount(&self) -> FatResult<()> {
+        let mut unmounted = self.unmounted.lock();
+        if *unmounted {
+            return Ok(());
+        }
+        // the volume is never marked dirty by a read only mount, so there is nothing to write
+        if self.read_only {
+            *unmounted = true;
+            return Ok(());
+        }
+        self.write_back_inodes()?;
+        self.fs.unmount()?;
+        self.cache.flush()?;
+        *unmounted = true;
+        Ok(())
+    }
+}
+
+impl Debug for FatSuperBlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str("FatSuperBlock")
+    }
+}
+
+impl DataOps for FatSuperBlock {
+    fn device(&self, name: &str) -> Option<Arc<dyn Device>> {
+        self.mount_data.as_ref().and_then(|data| data.device(name))
+    }
+    fn data(&self) -> *const u8 {
+        self as *const Self as *const u8
+    }
+}
 /// Description:
 ///
 /// Because the fatfs dont support inode,so we need save some information in inode.
@@ -24,20 +238,230 @@ type FatFile = File<FatDevice, DefaultTimeProvider, LossyOemCpConverter>;
 /// The information include the file name because original filesystem's inode include the inode number
 /// that can identify the file uniquely but fatfs dont have inode number.
 pub struct FatInode {
-    // parent
-    pub parent: Arc<Mutex<FatDir>>,
     // self: if the file is a directory,then the self is the directory's DIR struct.
     pub current: FatInodeType,
+    // the first cluster of a directory, it is 0 for the root of fat12/16 and for a file
+    pub cluster: u32,
+    // the position of the entry, it is the key of the inode table. 0 for the root.
+    pub offset: u64,
+    // the clusters of a file, it is built on the first read or write
+    extents: Mutex<Option<Arc<ExtentMap>>>,
+    // the attributes that the vfs inode has no place for
+    pub attr: Mutex<FatAttr>,
+    // the inode of the directory that holds the entry, none for the root
+    pub parent_inode: Option<Arc<Inode>>,
+}
+
+/// Description:
+///
+/// The attributes of an inode that are kept in memory. A change marks them dirty, they
+/// are saved in the entry of the inode when it is written back by flush, sync or unmount.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FatAttr {
+    /// the 512 byte blocks of the clusters of the file
+    pub blocks: u64,
+    /// the last change of the data
+    pub mtime: DosDateTime,
+    /// the day of the last access
+    pub atime: DosDateTime,
+    /// the last change of the data or the attributes, FAT does not save it
+    pub ctime: DosDateTime,
+    /// the creation and its 10 ms units
+    pub crtime: (DosDateTime, u8),
+    /// the entry on disk is older than the attributes
+    pub dirty: bool,
 }
 
+impl FatAttr {
+    pub fn new(entry: &RawDirEntry, cluster_size: u32) -> Self {
+        Self {
+            blocks: Self::blocks_of(entry.size() as u64, cluster_size),
+            mtime: entry.modified(),
+            atime: entry.accessed(),
+            ctime: entry.modified(),
+            crtime: entry.created(),
+            dirty: false,
+        }
+    }
+    /// The 512 byte blocks of the clusters of a file of `size` bytes
+    pub fn blocks_of(size: u64, cluster_size: u32) -> u64 {
+        let cluster_size = cluster_size as u64;
+        size.div_ceil(cluster_size) * cluster_size / 512
+    }
+}
+
+/// The fatfs handle of an inode, a lookup leaves it empty until it is needed. The slot
+/// stays locked while the handle is opened, so two users never open two handles.
 pub enum FatInodeType {
-    Dir(Arc<Mutex<FatDir>>),
-    File((String, Option<Arc<Mutex<FatFile>>>)),
+    Dir(Mutex<Option<Arc<Mutex<FatDir>>>>),
+    File(Mutex<Option<Arc<Mutex<FatFile>>>>),
+}
+
+impl FatInodeType {
+    pub fn dir(dir: Option<FatDir>) -> Self {
+        FatInodeType::Dir(Mutex::new(dir.map(|dir| Arc::new(Mutex::new(dir)))))
+    }
+    pub fn file(file: Option<FatFile>) -> Self {
+        FatInodeType::File(Mutex::new(file.map(|file| Arc::new(Mutex::new(file)))))
+    }
 }
 
 impl FatInode {
-    pub fn new(parent: Arc<Mutex<FatDir>>, current: FatInodeType) -> FatInode {
-        Self { parent, current }
+    pub fn new(current: FatInodeType, cluster: u32, offset: u64) -> FatInode {
+        Self {
+            current,
+            cluster,
+            offset,
+            extents: Mutex::new(None),
+            attr: Mutex::new(FatAttr::default()),
+            parent_inode: None,
+        }
+    }
+    /// The data of the file has changed, `size` is its new size
+    pub fn data_changed(&self, size: u64, fat_sb: &FatSuperBlock) {
+        let (now, _) = fat_sb.clock.now();
+        let mut attr = self.attr.lock();
+        attr.blocks = FatAttr::blocks_of(size, fat_sb.volume.cluster_size());
+        attr.mtime = now;
+        attr.ctime = now;
+        attr.dirty = true;
+    }
+    /// Save the attributes in the entry if they have changed
+    pub fn write_back(&self, volume: &Volume) -> FatResult<()> {
+        let mut attr = self.attr.lock();
+        // the root has no entry
+        if !attr.dirty || self.offset == 0 {
+            return Ok(());
+        }
+        let mut entry = volume.read_dir_entry(self.offset)?;
+        // a flush of fatfs may have written its own times, all of them are saved again
+        entry.set_modified(attr.mtime);
+        entry.set_accessed(attr.atime);
+        entry.set_created(attr.crtime.0, attr.crtime.1);
+        volume.write_dir_entry(self.offset, &entry)?;
+        attr.dirty = false;
+        Ok(())
+    }
+    /// Description:
+    ///
+    /// The extent map of the file, `first_cluster` and `size` come from its entry. A map of
+    /// another chain or a map that is too short for the size is built again, but a map
+    /// that has become too long can not be seen, so it must be dropped by
+    /// [FatInode::invalidate_extents] when the chain is cut.
+    pub fn extent_map(
+        &self,
+        volume: &Volume,
+        first_cluster: u32,
+        size: u32,
+    ) -> FatResult<Arc<ExtentMap>> {
+        let mut extents = self.extents.lock();
+        if let Some(map) = extents.as_ref()
+            && map.first_cluster() == first_cluster
+            && map.clusters() as u64 * volume.cluster_size() as u64 >= size as u64
+        {
+            return Ok(map.clone());
+        }
+        let map = Arc::new(ExtentMap::build(volume, first_cluster)?);
+        *extents = Some(map.clone());
+        Ok(map)
+    }
+    /// Drop the extent map after the chain of the file has changed
+    pub fn invalidate_extents(&self) {
+        *self.extents.lock() = None;
+    }
+    /// Description:
+    ///
+    /// The fatfs directory of a directory inode. A lookup builds the inode from the entry
+    /// on disk only, the directory is opened through its parent the first time it is
+    /// needed, e.g. to create a file in it.
+    pub fn fat_dir(&self, volume: &Volume) -> FatResult<Arc<Mutex<FatDir>>> {
+        let FatInodeType::Dir(slot) = &self.current else {
+            return Err(FatError::NotDir);
+        };
+        // the lock of the parent is taken while the slot is held, never the other way
+        let mut slot = slot.lock();
+        if let Some(dir) = slot.as_ref() {
+            return Ok(dir.clone());
+        }
+        let dir = self.fatfs_entry(volume)?.to_dir();
+        let dir = Arc::new(Mutex::new(dir));
+        *slot = Some(dir.clone());
+        Ok(dir)
+    }
+    /// The fatfs file of a file inode, it is opened like [FatInode::fat_dir]
+    pub fn fat_file(&self, volume: &Volume) -> FatResult<Arc<Mutex<FatFile>>> {
+        let FatInodeType::File(slot) = &self.current else {
+            return Err(FatError::IsDir);
+        };
+        let mut slot = slot.lock();
+        if let Some(file) = slot.as_ref() {
+            return Ok(file.clone());
+        }
+        let file = self.fatfs_entry(volume)?.to_file();
+        let file = Arc::new(Mutex::new(file));
+        *slot = Some(file.clone());
+        Ok(file)
+    }
+    /// The fatfs file if it is open, a file that has not been opened has no state in fatfs
+    pub fn open_fat_file(&self) -> Option<Arc<Mutex<FatFile>>> {
+        match &self.current {
+            FatInodeType::File(slot) => slot.lock().clone(),
+            FatInodeType::Dir(_) => None,
+        }
+    }
+    /// Description:
+    ///
+    /// The fatfs entry of the inode. fatfs would open it by its name, but it takes
+    /// STRASSE.TXT for an earlier straße.txt too. So the entry is taken at the position of
+    /// the short entry: fatfs lists the same entries as [Volume::walk_dir_items]. The
+    /// short name is compared, a directory that changed in between is not trusted.
+    fn fatfs_entry(&self, volume: &Volume) -> FatResult<FatDirEntry> {
+        let parent = self.parent_inode.clone().ok_or(FatError::InvalidArgument)?;
+        let mut index = 0;
+        let mut found = None;
+        volume.walk_dir_items(get_fat_data(parent).cluster, 0, |item, _| {
+            if item.offset == self.offset {
+                found = Some(item.entry);
+                return false;
+            }
+            index += 1;
+            true
+        })?;
+        let short_name = found.ok_or(fatfs::Error::NotFound)?.fatfs_short_name();
+        let parent_dir = self.parent_dir(volume)?;
+        let entry = parent_dir.lock().iter().nth(index).transpose()?;
+        match entry {
+            Some(entry) if entry.short_file_name_as_bytes() == short_name.as_slice() => Ok(entry),
+            _ => Err(fatfs::Error::CorruptedFileSystem.into()),
+        }
+    }
+    /// The fatfs directory of the directory that holds the entry
+    pub fn parent_dir(&self, volume: &Volume) -> FatResult<Arc<Mutex<FatDir>>> {
+        let parent = self.parent_inode.clone().ok_or(FatError::InvalidArgument)?;
+        get_fat_data(parent).fat_dir(volume)
+    }
+    /// Description:
+    ///
+    /// The entry has moved. A fatfs handle writes the entry it was opened from, so it is
+    /// closed and opened again at the new place when it is needed. The caller must not
+    /// hold the lock of a fatfs directory.
+    pub fn moved(&self) {
+        match &self.current {
+            FatInodeType::Dir(slot) => *slot.lock() = None,
+            FatInodeType::File(slot) => *slot.lock() = None,
+        }
+    }
+    /// The name that fatfs finds the entry by, it is found by the position of the entry
+    pub fn fatfs_name(&self, volume: &Volume) -> FatResult<String> {
+        let parent = self.parent_inode.clone().ok_or(FatError::InvalidArgument)?;
+        let mut name = None;
+        volume.walk_dir_items(get_fat_data(parent).cluster, 0, |item, _| {
+            if item.offset == self.offset {
+                name = Some(item.fatfs_name(&volume.codepage()));
+            }
+            name.is_none()
+        })?;
+        name.ok_or(fatfs::Error::NotFound.into())
     }
 }
 
@@ -65,3 +489,14 @@ fn get_fat_data(inode: Arc<Inode>) -> &'static mut FatInode {
     let data = inode_inner.data.as_ref().unwrap();
     unsafe { &mut *(data.data() as *mut FatInode) }
 }
+
+fn get_fat_sb(sb_blk: &SuperBlock) -> &FatSuperBlock {
+    let data = sb_blk.data.as_ref().unwrap();
+    unsafe { &*(data.data() as *const FatSuperBlock) }
+}
+
+/// Return an error if the filesystem of the inode is mounted read only
+fn check_writable(inode: &Inode) -> FatResult<()> {
+    let sb_blk = inode.super_blk.upgrade().unwrap();
+    get_fat_sb(&sb_blk).check_writable()
+}
@@ -0,0 +1,160 @@
+use crate::cache::CachePolicy;
+use crate::codepage::CodePage;
+use crate::error::{FatError, FatResult};
+use crate::partition::{Guid, PartitionSelector};
+use crate::time::Clock;
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use core::fmt::{Debug, Formatter};
+use rvfs::superblock::{DataOps, Device};
+use spin::Mutex;
+
+/// Description:
+///
+/// The options of a fat mount. They are passed through the `data` method of the mount
+/// data, [FatMountData] does that for you. Any other data, or a null pointer, mounts
+/// with the default options.
+///
+/// The options can also be parsed from a mount string like `cache=256,cache_mode=writeback`.
+/// The clock can not, it is set in the options that the string gives.
+#[derive(Debug, Clone)]
+pub struct FatMountOptions {
+    /// the number of sectors kept in the block cache, 0 disables the cache
+    pub cache_blocks: usize,
+    pub cache_policy: CachePolicy,
+    /// the partition of the device that holds the filesystem
+    pub partition: PartitionSelector,
+    /// nothing is written to the device, it is also set by a read only mount flag
+    pub read_only: bool,
+    /// the code page of the 8.3 short names
+    pub codepage: CodePage,
+    /// the clock of the kernel, the times of the entries are wrong without it
+    pub clock: Option<Arc<dyn Clock>>,
+    /// the minutes the times on disk are ahead of UTC, `None` takes the offset of the
+    /// clock. It is set by `time_offset=`, `tz=UTC` sets it to 0.
+    pub time_offset: Option<i32>,
+}
+
+impl Default for FatMountOptions {
+    fn default() -> Self {
+        Self {
+            cache_blocks: 1024,
+            cache_policy: CachePolicy::WriteBack,
+            partition: PartitionSelector::Whole,
+            read_only: false,
+            codepage: CodePage::default(),
+            clock: None,
+            time_offset: None,
+        }
+    }
+}
+
+impl FatMountOptions {
+    /// Parse the comma separated `key=value` options
+    pub fn parse(options: &str) -> FatResult<Self> {
+        let mut res = Self::default();
+        for option in options.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let (key, value) = option.split_once('=').unwrap_or((option, ""));
+            match key {
+                "cache" => {
+                    res.cache_blocks = value.parse().map_err(|_| FatError::InvalidArgument)?;
+                }
+                "cache_mode" => {
+                    res.cache_policy = match value {
+                        "writeback" => CachePolicy::WriteBack,
+                        "writethrough" => CachePolicy::WriteThrough,
+                        _ => return Err(FatError::InvalidArgument),
+                    };
+                }
+                "ro" => res.read_only = true,
+                "rw" => res.read_only = false,
+                "partition" => {
+                    let index = value.parse().map_err(|_| FatError::InvalidArgument)?;
+                    res.partition = PartitionSelector::Index(index);
+                }
+                "partition_guid" => {
+                    res.partition = PartitionSelector::Guid(Guid::parse(value)?);
+                }
+                "partition_label" => {
+                    res.partition = PartitionSelector::Label(value.to_string());
+                }
+                "codepage" => {
+                    let number = value.parse().map_err(|_| FatError::InvalidArgument)?;
+                    res.codepage =
+                        CodePage::from_number(number).ok_or(FatError::InvalidArgument)?;
+                }
+                "tz" if value == "UTC" => res.time_offset = Some(0),
+                "time_offset" => {
+                    let offset = value.parse().map_err(|_| FatError::InvalidArgument)?;
+                    // a day at most, like linux
+                    if !(-24 * 60..=24 * 60).contains(&offset) {
+                        return Err(FatError::InvalidArgument);
+                    }
+                    res.time_offset = Some(offset);
+                }
+                _ => return Err(FatError::InvalidArgument),
+            }
+        }
+        Ok(res)
+    }
+
+    /// Description:
+    ///
+    /// Get the options from the mount data. The pointer is only taken as options when it
+    /// is the options of a live [FatMountData], so the data of another filesystem, or a
+    /// null pointer, gives the default options without being read.
+    pub(crate) fn from_data(data: &dyn DataOps) -> Self {
+        let ptr = data.data();
+        if !LIVE_OPTIONS.lock().contains(&(ptr as usize)) {
+            return Self::default();
+        }
+        // the address belongs to the boxed options of a FatMountData, the data is
+        // borrowed so it is not dropped while we read it
+        unsafe { (*(ptr as *const FatMountOptions)).clone() }
+    }
+}
+
+/// The addresses of the options of the [FatMountData] that are alive
+static LIVE_OPTIONS: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+
+/// The mount data with a device and the mount options
+pub struct FatMountData {
+    device: Arc<dyn Device>,
+    // boxed so the address stays the same when the data is moved
+    options: Box<FatMountOptions>,
+}
+
+impl FatMountData {
+    pub fn new(device: Arc<dyn Device>, options: FatMountOptions) -> Self {
+        let options = Box::new(options);
+        LIVE_OPTIONS.lock().insert(&*options as *const _ as usize);
+        Self { device, options }
+    }
+}
+
+impl Drop for FatMountData {
+    fn drop(&mut self) {
+        LIVE_OPTIONS
+            .lock()
+            .remove(&(&*self.options as *const _ as usize));
+    }
+}
+
+impl Debug for FatMountData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FatMountData")
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl DataOps for FatMountData {
+    fn device(&self, _name: &str) -> Option<Arc<dyn Device>> {
+        Some(self.device.clone())
+    }
+    fn data(&self) -> *const u8 {
+        &*self.options as *const FatMountOptions as *const u8
+    }
+}
@@ -0,0 +1,308 @@
+use crate::error::{FatError, FatResult};
+use crate::fstype::FatDevice;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Display, Formatter};
+
+/// The sector size used by the partition tables
+const SECTOR_SIZE: u64 = 512;
+/// The max number of logical partitions in an extended partition, it stops a loop in
+/// a broken EBR chain.
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const MBR_PROTECTIVE: u8 = 0xEE;
+
+/// A GUID as it is stored in the GPT: the first three fields are little endian
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// Parse a GUID like `C12A7328-F81F-11D2-BA4B-00A0C93EC93B`
+    pub fn parse(guid: &str) -> FatResult<Self> {
+        let hex: Vec<u8> = guid.bytes().filter(|x| *x != b'-').collect();
+        if hex.len() != 32 || guid.len() != 36 {
+            return Err(FatError::InvalidArgument);
+        }
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let s = core::str::from_utf8(&hex[i * 2..i * 2 + 2])
+                .map_err(|_| FatError::InvalidArgument)?;
+            *byte = u8::from_str_radix(s, 16).map_err(|_| FatError::InvalidArgument)?;
+        }
+        // the text form is big endian
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        Ok(Guid(bytes))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|x| *x == 0)
+    }
+}
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6]
+        )?;
+        write!(f, "{:02X}{:02X}-", b[8], b[9])?;
+        b[10..].iter().try_for_each(|x| write!(f, "{:02X}", x))
+    }
+}
+
+impl Debug for Guid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PartitionKind {
+    Mbr {
+        system_id: u8,
+        bootable: bool,
+    },
+    Gpt {
+        type_guid: Guid,
+        unique_guid: Guid,
+        name: String,
+    },
+}
+
+/// A partition of a disk, the start and the length are in bytes
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// The number of the partition, it starts from 1. The logical partitions of a MBR
+    /// start from 5 like linux does.
+    pub number: u32,
+    pub start: u64,
+    pub len: u64,
+    pub kind: PartitionKind,
+}
+
+/// How the partition to mount is chosen
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum PartitionSelector {
+    /// the filesystem starts at the first byte of the device
+    #[default]
+    Whole,
+    /// the number of the partition
+    Index(u32),
+    /// the unique GUID of a GPT partition
+    Guid(Guid),
+    /// the name of a GPT partition
+    Label(String),
+}
+
+impl PartitionSelector {
+    fn matches(&self, partition: &Partition) -> bool {
+        match (self, &partition.kind) {
+            (PartitionSelector::Index(n), _) => partition.number == *n,
+            (PartitionSelector::Guid(guid), PartitionKind::Gpt { unique_guid, .. }) => {
+                unique_guid == guid
+            }
+            (PartitionSelector::Label(label), PartitionKind::Gpt { name, .. }) => name == label,
+            _ => false,
+        }
+    }
+}
+
+/// Read the partition table of the disk. A disk with a protective MBR is read as GPT.
+pub fn read_partitions(device: &mut FatDevice) -> FatResult<Vec<Partition>> {
+    let mut mbr = [0u8; SECTOR_SIZE as usize];
+    device.read_at(0, &mut mbr)?;
+    if mbr[510..512] != [0x55, 0xAA] {
+        return Err(fatfs::Error::CorruptedFileSystem.into());
+    }
+    let is_gpt = (0..4).any(|i| mbr[446 + i * 16 + 4] == MBR_PROTECTIVE);
+    if is_gpt {
+        read_gpt(device)
+    } else {
+        read_mbr(device, &mbr)
+    }
+}
+
+/// Find the partition chosen by the selector
+pub fn find_partition(
+    device: &mut FatDevice,
+    selector: &PartitionSelector,
+) -> FatResult<Option<Partition>> {
+    if *selector == PartitionSelector::Whole {
+        return Ok(None);
+    }
+    read_partitions(device)?
+        .into_iter()
+        .find(|x| selector.matches(x))
+        .map(Some)
+        .ok_or(fatfs::Error::NotFound.into())
+}
+
+struct MbrEntry {
+    bootable: bool,
+    system_id: u8,
+    lba: u64,
+    sectors: u64,
+}
+
+fn mbr_entry(sector: &[u8], index: usize) -> MbrEntry {
+    let entry = &sector[446 + index * 16..446 + (index + 1) * 16];
+    MbrEntry {
+        bootable: entry[0] & 0x80 != 0,
+        system_id: entry[4],
+        lba: u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64,
+        sectors: u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64,
+    }
+}
+
+fn is_extended(system_id: u8) -> bool {
+    matches!(system_id, 0x05 | 0x0F | 0x85)
+}
+
+fn read_mbr(device: &mut FatDevice, mbr: &[u8]) -> FatResult<Vec<Partition>> {
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for i in 0..4 {
+        let entry = mbr_entry(mbr, i);
+        if entry.system_id == 0 || entry.sectors == 0 {
+            continue;
+        }
+        if is_extended(entry.system_id) {
+            extended = Some(entry.lba);
+            continue;
+        }
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            start: entry.lba * SECTOR_SIZE,
+            len: entry.sectors * SECTOR_SIZE,
+            kind: PartitionKind::Mbr {
+                system_id: entry.system_id,
+                bootable: entry.bootable,
+            },
+        });
+    }
+    // the logical partitions are a list of EBR, the first entry of an EBR is relative
+    // to the EBR itself and the second one points to the next EBR, relative to the
+    // start of the extended partition
+    if let Some(extended_lba) = extended {
+        let mut ebr_lba = extended_lba;
+        let mut ebr = [0u8; SECTOR_SIZE as usize];
+        for number in 5..5 + MAX_LOGICAL_PARTITIONS as u32 {
+            device.read_at(ebr_lba * SECTOR_SIZE, &mut ebr)?;
+            if ebr[510..512] != [0x55, 0xAA] {
+                return Err(fatfs::Error::CorruptedFileSystem.into());
+            }
+            let logical = mbr_entry(&ebr, 0);
+            if logical.system_id != 0 && logical.sectors != 0 {
+                partitions.push(Partition {
+                    number,
+                    start: (ebr_lba + logical.lba) * SECTOR_SIZE,
+                    len: logical.sectors * SECTOR_SIZE,
+                    kind: PartitionKind::Mbr {
+                        system_id: logical.system_id,
+                        bootable: logical.bootable,
+                    },
+                });
+            }
+            let next = mbr_entry(&ebr, 1);
+            if next.lba == 0 || !is_extended(next.system_id) {
+                break;
+            }
+            ebr_lba = extended_lba + next.lba;
+        }
+    }
+    Ok(partitions)
+}
+
+fn read_gpt(device: &mut FatDevice) -> FatResult<Vec<Partition>> {
+    let mut header = [0u8; 512];
+    // the header is in the second logical block, the block size may be 512 or 4096
+    let mut block_size = SECTOR_SIZE;
+    device.read_at(block_size, &mut header)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        block_size = 4096;
+        device.read_at(block_size, &mut header)?;
+        if &header[0..8] != GPT_SIGNATURE {
+            return Err(fatfs::Error::CorruptedFileSystem.into());
+        }
+    }
+    let u32_at = |pos: usize| u32::from_le_bytes(header[pos..pos + 4].try_into().unwrap());
+    let u64_at = |pos: usize| u64::from_le_bytes(header[pos..pos + 8].try_into().unwrap());
+    let header_size = u32_at(12) as usize;
+    if !(92..=header.len()).contains(&header_size) {
+        return Err(fatfs::Error::CorruptedFileSystem.into());
+    }
+    let mut crc_header = header;
+    crc_header[16..20].fill(0);
+    if crc32(&crc_header[..header_size]) != u32_at(16) {
+        return Err(fatfs::Error::CorruptedFileSystem.into());
+    }
+    let entries_lba = u64_at(72);
+    let entries = u32_at(80) as usize;
+    let entry_size = u32_at(84) as usize;
+    // an entry is 128 << n bytes and never crosses a block
+    let valid_size =
+        entry_size >= 128 && entry_size.is_power_of_two() && entry_size as u64 <= block_size;
+    if !valid_size || entries > 1024 {
+        return Err(fatfs::Error::CorruptedFileSystem.into());
+    }
+    let corrupted = || FatError::from(fatfs::Error::CorruptedFileSystem);
+    let table_len = entries.checked_mul(entry_size).ok_or_else(corrupted)?;
+    let table_offset = entries_lba.checked_mul(block_size).ok_or_else(corrupted)?;
+    let mut table = vec![0u8; table_len];
+    device.read_at(table_offset, &mut table)?;
+    if crc32(&table) != u32_at(88) {
+        return Err(fatfs::Error::CorruptedFileSystem.into());
+    }
+    let mut partitions = Vec::new();
+    for (i, entry) in table.chunks(entry_size).enumerate() {
+        let type_guid = Guid(entry[0..16].try_into().unwrap());
+        if type_guid.is_zero() {
+            continue;
+        }
+        let first_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        let last_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        if last_lba < first_lba {
+            return Err(corrupted());
+        }
+        let start = first_lba.checked_mul(block_size).ok_or_else(corrupted)?;
+        let len = (last_lba - first_lba + 1)
+            .checked_mul(block_size)
+            .ok_or_else(corrupted)?;
+        let name = entry[56..128]
+            .chunks(2)
+            .map(|x| u16::from_le_bytes([x[0], x[1]]))
+            .take_while(|x| *x != 0);
+        let name = char::decode_utf16(name)
+            .map(|x| x.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            start,
+            len,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                unique_guid: Guid(entry[16..32].try_into().unwrap()),
+                name,
+            },
+        });
+    }
+    Ok(partitions)
+}
+
+/// The CRC32 used by GPT
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,172 @@
+use crate::disk::DosDateTime;
+use alloc::sync::Arc;
+use core::fmt::Debug;
+use fatfs::{Date, DateTime, DefaultTimeProvider, Time, TimeProvider};
+
+/// The seconds from the Unix epoch to 1980-01-01, the first day FAT can store
+const FAT_EPOCH: i64 = 315_532_800;
+/// The last time FAT can store, 2107-12-31 23:59:58
+const FAT_END: i64 = 4_354_819_198;
+const SECS_PER_DAY: i64 = 24 * 3600;
+
+/// A time in seconds and nanoseconds since the Unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Timespec {
+    pub sec: i64,
+    pub nsec: u32,
+}
+
+impl Timespec {
+    pub const fn new(sec: i64, nsec: u32) -> Self {
+        Self { sec, nsec }
+    }
+}
+
+/// The days from 1970-01-01 to a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// The year, month and day of a number of days from 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DosDateTime {
+    /// Description:
+    ///
+    /// The time since the Unix epoch, `centis` are the 10 ms units that a creation time
+    /// adds to the seconds, 0..200. A zero date, as the root and some old tools leave it,
+    /// is 1980-01-01.
+    pub fn to_timespec(self, centis: u8) -> Timespec {
+        let year = 1980 + (self.date >> 9) as i64;
+        let month = ((self.date >> 5) & 0xF).clamp(1, 12) as i64;
+        let day = (self.date & 0x1F).max(1) as i64;
+        let secs = (self.time >> 11) as i64 * 3600
+            + ((self.time >> 5) & 0x3F) as i64 * 60
+            + (self.time & 0x1F) as i64 * 2;
+        let centis = centis.min(199) as i64;
+        Timespec {
+            sec: days_from_civil(year, month, day) * SECS_PER_DAY + secs + centis / 100,
+            nsec: (centis % 100) as u32 * 10_000_000,
+        }
+    }
+
+    /// Description:
+    ///
+    /// The nearest earlier time FAT can store and the 10 ms units over its 2 seconds.
+    /// A time before 1980 or after 2107 is moved to the first or the last one.
+    pub fn from_timespec(time: Timespec) -> (Self, u8) {
+        let (sec, nsec) = match time.sec {
+            sec if sec < FAT_EPOCH => (FAT_EPOCH, 0),
+            sec if sec > FAT_END + 1 => (FAT_END + 1, 999_999_999),
+            sec => (sec, time.nsec.min(999_999_999)),
+        };
+        let (year, month, day) = civil_from_days(sec.div_euclid(SECS_PER_DAY));
+        let secs = sec.rem_euclid(SECS_PER_DAY);
+        let date = ((year - 1980) << 9 | month << 5 | day) as u16;
+        let time = ((secs / 3600) << 11 | (secs / 60 % 60) << 5 | (secs % 60 / 2)) as u16;
+        let centis = (secs % 2 * 100) as u8 + (nsec / 10_000_000) as u8;
+        (DosDateTime { date, time }, centis)
+    }
+}
+
+/// Description:
+///
+/// The clock of the kernel, it is given to a mount in [crate::options::FatMountOptions].
+/// Without it the times come from the `DefaultTimeProvider` of fatfs, which is a fixed
+/// date in `no_std`.
+pub trait Clock: Debug + Send + Sync {
+    /// The current time, UTC
+    fn now(&self) -> Timespec;
+    /// The minutes the local time is ahead of UTC
+    fn utc_offset(&self) -> i32 {
+        0
+    }
+}
+
+/// Description:
+///
+/// The time source of a mount. The entries store the local time like Windows does, this
+/// converts between it and the Unix time of the vfs. It is the `TimeProvider` of fatfs
+/// too, so the entries that fatfs writes get the same times as the ones written here.
+#[derive(Debug, Clone, Default)]
+pub struct FatClock {
+    clock: Option<Arc<dyn Clock>>,
+    /// the minutes the times on disk are ahead of UTC
+    offset: i32,
+}
+
+impl FatClock {
+    /// `offset` is the `time_offset` option, the offset of the clock is used without it
+    pub fn new(clock: Option<Arc<dyn Clock>>, offset: Option<i32>) -> Self {
+        let offset = offset.unwrap_or_else(|| clock.as_ref().map_or(0, |x| x.utc_offset()));
+        Self { clock, offset }
+    }
+
+    pub fn offset(&self) -> i32 {
+        self.offset
+    }
+
+    /// The Unix time of a time on disk
+    pub fn to_timespec(&self, time: DosDateTime, centis: u8) -> Timespec {
+        let mut time = time.to_timespec(centis);
+        time.sec -= self.offset as i64 * 60;
+        time
+    }
+
+    /// The time on disk of a Unix time, see [DosDateTime::from_timespec]
+    pub fn to_dos(&self, mut time: Timespec) -> (DosDateTime, u8) {
+        time.sec = time.sec.saturating_add(self.offset as i64 * 60);
+        DosDateTime::from_timespec(time)
+    }
+
+    /// The current time as it is stored on disk
+    pub fn now(&self) -> (DosDateTime, u8) {
+        match &self.clock {
+            Some(clock) => self.to_dos(clock.now()),
+            None => {
+                let now = DefaultTimeProvider::new().get_current_date_time();
+                let centis = (now.time.sec % 2 * 100 + now.time.millis / 10) as u8;
+                (DosDateTime::from(now), centis)
+            }
+        }
+    }
+}
+
+impl TimeProvider for FatClock {
+    fn get_current_date(&self) -> Date {
+        self.get_current_date_time().date
+    }
+
+    fn get_current_date_time(&self) -> DateTime {
+        let (now, centis) = self.now();
+        let date = Date::new(
+            1980 + (now.date >> 9),
+            (now.date >> 5) & 0xF,
+            now.date & 0x1F,
+        );
+        let time = Time::new(
+            now.time >> 11,
+            (now.time >> 5) & 0x3F,
+            (now.time & 0x1F) * 2 + centis as u16 / 100,
+            centis as u16 % 100 * 10,
+        );
+        DateTime::new(date, time)
+    }
+}
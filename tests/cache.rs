@@ -0,0 +1,219 @@
+mod common;
+
+use common::MemDevice;
+use fat32_vfs::cache::{BlockCache, CachePolicy};
+use rvfs::info::VfsError;
+use rvfs::superblock::Device;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+const BLOCK: usize = 512;
+
+/// A memory device that counts the reads, the writes and the flushes that reach it
+#[derive(Debug)]
+struct CountingDevice {
+    mem: MemDevice,
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+    flushes: AtomicUsize,
+}
+
+impl CountingDevice {
+    fn new(blocks: usize) -> Arc<Self> {
+        let data = (0..blocks * BLOCK).map(|x| (x / BLOCK) as u8).collect();
+        Arc::new(CountingDevice {
+            mem: MemDevice::new(data),
+            reads: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+            flushes: AtomicUsize::new(0),
+        })
+    }
+
+    fn reads(&self) -> usize {
+        self.reads.load(Ordering::SeqCst)
+    }
+
+    fn writes(&self) -> usize {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    fn block(&self, id: usize) -> Vec<u8> {
+        self.mem.bytes()[id * BLOCK..(id + 1) * BLOCK].to_vec()
+    }
+}
+
+impl Device for CountingDevice {
+    fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, VfsError> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.mem.read(buf, offset)
+    }
+
+    fn write(&self, buf: &[u8], offset: usize) -> Result<usize, VfsError> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.mem.write(buf, offset)
+    }
+
+    fn size(&self) -> usize {
+        self.mem.size()
+    }
+
+    fn flush(&self) {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn read_block(cache: &BlockCache, id: usize) -> u8 {
+    let mut buf = [0u8; 1];
+    assert_eq!(cache.read(&mut buf, id * BLOCK + 7).unwrap(), 1);
+    buf[0]
+}
+
+#[test]
+fn evict_least_recently_used() {
+    let device = CountingDevice::new(4);
+    let cache = BlockCache::new(device.clone(), BLOCK, 2, CachePolicy::WriteBack);
+    assert_eq!(read_block(&cache, 0), 0);
+    assert_eq!(read_block(&cache, 1), 1);
+    assert_eq!(device.reads(), 2);
+    // block 0 becomes the most recently used one
+    assert_eq!(read_block(&cache, 0), 0);
+    assert_eq!(device.reads(), 2);
+    // block 1 is evicted for block 2
+    assert_eq!(read_block(&cache, 2), 2);
+    assert_eq!(device.reads(), 3);
+    assert_eq!(read_block(&cache, 0), 0);
+    assert_eq!(device.reads(), 3);
+    assert_eq!(read_block(&cache, 1), 1);
+    assert_eq!(device.reads(), 4);
+}
+
+#[test]
+fn write_back_until_flush() {
+    let device = CountingDevice::new(4);
+    let cache = BlockCache::new(device.clone(), BLOCK, 4, CachePolicy::WriteBack);
+    cache.write(&[0xAA; 16], BLOCK + 100).unwrap();
+    assert_eq!(device.writes(), 0);
+    assert_eq!(device.block(1)[100], 1);
+    // the cache returns its own data before the flush
+    let mut buf = [0u8; 16];
+    cache.read(&mut buf, BLOCK + 100).unwrap();
+    assert_eq!(buf, [0xAA; 16]);
+
+    cache.flush().unwrap();
+    assert_eq!(device.writes(), 1);
+    assert_eq!(device.flushes.load(Ordering::SeqCst), 1);
+    let block = device.block(1);
+    assert_eq!(&block[100..116], &[0xAA; 16]);
+    assert_eq!(block[99], 1);
+    assert_eq!(block[116], 1);
+    // a clean block is not written again
+    cache.flush().unwrap();
+    assert_eq!(device.writes(), 1);
+}
+
+#[test]
+fn write_back_on_eviction() {
+    let device = CountingDevice::new(4);
+    let cache = BlockCache::new(device.clone(), BLOCK, 1, CachePolicy::WriteBack);
+    cache.write(&[0xBB; 4], 0).unwrap();
+    assert_eq!(device.writes(), 0);
+    assert_eq!(read_block(&cache, 3), 3);
+    assert_eq!(device.writes(), 1);
+    assert_eq!(&device.block(0)[..4], &[0xBB; 4]);
+}
+
+#[test]
+fn write_through() {
+    let device = CountingDevice::new(4);
+    let cache = BlockCache::new(device.clone(), BLOCK, 4, CachePolicy::WriteThrough);
+    cache.write(&[0xCC; 8], 2 * BLOCK).unwrap();
+    assert_eq!(device.writes(), 1);
+    assert_eq!(&device.block(2)[..8], &[0xCC; 8]);
+    // nothing is dirty, the flush only flushes the device
+    cache.flush().unwrap();
+    assert_eq!(device.writes(), 1);
+    let mut buf = [0u8; 8];
+    cache.read(&mut buf, 2 * BLOCK).unwrap();
+    assert_eq!(buf, [0xCC; 8]);
+}
+
+#[test]
+fn full_block_write_skips_read() {
+    let device = CountingDevice::new(4);
+    let cache = BlockCache::new(device.clone(), BLOCK, 4, CachePolicy::WriteBack);
+    cache.write(&[0xDD; BLOCK], BLOCK).unwrap();
+    assert_eq!(device.reads(), 0);
+    cache.flush().unwrap();
+    assert_eq!(device.block(1), vec![0xDD; BLOCK]);
+}
+
+#[test]
+fn capacity_zero_passes_through() {
+    let device = CountingDevice::new(4);
+    let cache = BlockCache::new(device.clone(), BLOCK, 0, CachePolicy::WriteBack);
+    assert_eq!(read_block(&cache, 1), 1);
+    assert_eq!(read_block(&cache, 1), 1);
+    assert_eq!(device.reads(), 2);
+    cache.write(&[0xEE; 3], 3 * BLOCK).unwrap();
+    assert_eq!(device.writes(), 1);
+    assert_eq!(&device.block(3)[..3], &[0xEE; 3]);
+}
+
+#[test]
+fn read_across_blocks_and_the_end() {
+    let device = CountingDevice::new(4);
+    let cache = BlockCache::new(device.clone(), BLOCK, 4, CachePolicy::WriteBack);
+    let mut buf = [0u8; BLOCK + 2];
+    assert_eq!(cache.read(&mut buf, BLOCK - 1).unwrap(), BLOCK + 2);
+    assert_eq!(buf[0], 0);
+    assert_eq!(buf[1], 1);
+    assert_eq!(buf[BLOCK + 1], 2);
+    // the read stops at the end of the device
+    assert_eq!(cache.read(&mut buf, 4 * BLOCK - 10).unwrap(), 10);
+    assert_eq!(cache.read(&mut buf, 4 * BLOCK).unwrap(), 0);
+}
+
+#[test]
+fn invalidate_drops_dirty_blocks() {
+    let device = CountingDevice::new(4);
+    let cache = BlockCache::new(device.clone(), BLOCK, 4, CachePolicy::WriteBack);
+    cache.write(&[0xFF; 4], 0).unwrap();
+    cache.invalidate();
+    cache.flush().unwrap();
+    assert_eq!(device.writes(), 0);
+    assert_eq!(read_block(&cache, 0), 0);
+}
+
+#[test]
+fn direct_read_skips_the_cache() {
+    let device = CountingDevice::new(8);
+    let cache = BlockCache::new(device.clone(), BLOCK, 2, CachePolicy::WriteBack);
+    assert_eq!(read_block(&cache, 0), 0);
+    cache.write(&[0xAB; 4], BLOCK + 8).unwrap();
+    // one read of the device for the whole range, the dirty block is written first
+    let mut buf = vec![0u8; 4 * BLOCK];
+    assert_eq!(cache.read_direct(&mut buf, BLOCK).unwrap(), 4 * BLOCK);
+    assert_eq!(device.reads(), 3);
+    assert_eq!(device.writes(), 1);
+    assert_eq!(&buf[8..12], &[0xAB; 4]);
+    assert_eq!(buf[3 * BLOCK], 4);
+    // the cached blocks are still there
+    assert_eq!(read_block(&cache, 0), 0);
+    assert_eq!(read_block(&cache, 1), 1);
+    assert_eq!(device.reads(), 3);
+}
+
+#[test]
+fn direct_write_drops_cached_blocks() {
+    let device = CountingDevice::new(8);
+    let cache = BlockCache::new(device.clone(), BLOCK, 4, CachePolicy::WriteBack);
+    assert_eq!(read_block(&cache, 2), 2);
+    cache.write(&[0xCD; 4], 3 * BLOCK).unwrap();
+    cache.write_direct(&[0xEF; 2 * BLOCK], 2 * BLOCK).unwrap();
+    assert_eq!(device.block(2), vec![0xEF; BLOCK]);
+    assert_eq!(device.block(3), vec![0xEF; BLOCK]);
+    // the old data is neither read nor written back
+    assert_eq!(read_block(&cache, 2), 0xEF);
+    cache.flush().unwrap();
+    assert_eq!(device.block(3), vec![0xEF; BLOCK]);
+}
@@ -0,0 +1,287 @@
+mod common;
+
+use common::{mount, parse_dirents, vfs_lock, MemDevice};
+use fat32_vfs::codepage::CodePage;
+use fat32_vfs::disk::Volume;
+use fat32_vfs::file::FAT_DENTRY_OPS;
+use fat32_vfs::format::{format_volume, FormatOptions};
+use fat32_vfs::fsck::check;
+use fat32_vfs::fstype::FatDevice;
+use fat32_vfs::inode::FAT_INODE_DIR_OPS;
+use fat32_vfs::options::{FatMountData, FatMountOptions};
+use fatfs::{FileSystem, FsOptions};
+use rvfs::dentry::{DirEntry, DirFlags};
+use rvfs::file::{vfs_mkdir, vfs_open_file, vfs_readdir, File, FileMode, OpenFlags};
+use rvfs::FakeFSC;
+use std::sync::{Arc, MutexGuard};
+
+const MIB: usize = 1024 * 1024;
+
+/// Mount a volume with the short names `0x9B A.TXT` and `0xE5 B.TXT`, like Windows writes
+/// them for names that fit 8.3 in the code page
+fn mount_oem_names(options: &str) -> MutexGuard<'static, ()> {
+    let device = Arc::new(MemDevice::zeroed(16 * MIB));
+    format_volume(device.clone(), FormatOptions::new()).unwrap();
+    let fs = FileSystem::new(FatDevice::new(device.clone()), FsOptions::new()).unwrap();
+    fs.root_dir().create_file("A.TXT").unwrap();
+    fs.root_dir().create_file("B.TXT").unwrap();
+    fs.unmount().unwrap();
+
+    let volume = Volume::new(FatDevice::new(device.clone())).unwrap();
+    for (offset, mut entry) in volume.read_dir(0).unwrap() {
+        let name = match entry.name() {
+            b"A       TXT" => b"\x9BA      TXT",
+            // a first byte 0xE5 is saved as 0x05
+            b"B       TXT" => b"\x05B      TXT",
+            _ => continue,
+        };
+        entry.0[..11].copy_from_slice(name);
+        volume.write_dir_entry(offset, &entry).unwrap();
+    }
+
+    let lock = vfs_lock();
+    let options = FatMountOptions::parse(options).unwrap();
+    mount(Some(Box::new(FatMountData::new(device, options)))).unwrap();
+    lock
+}
+
+/// Mount a volume with the short names `中文.TXT` and `文件.TXT` in CP936 and no long
+/// names, like Chinese Windows writes them. The writes reach the device at once.
+fn mount_gbk_names() -> (MutexGuard<'static, ()>, Arc<MemDevice>) {
+    let device = Arc::new(MemDevice::zeroed(16 * MIB));
+    format_volume(device.clone(), FormatOptions::new()).unwrap();
+    let fs = FileSystem::new(FatDevice::new(device.clone()), FsOptions::new()).unwrap();
+    fs.root_dir().create_file("A.TXT").unwrap();
+    fs.root_dir().create_file("B.TXT").unwrap();
+    fs.unmount().unwrap();
+
+    let volume = Volume::new(FatDevice::new(device.clone())).unwrap();
+    for (offset, mut entry) in volume.read_dir(0).unwrap() {
+        let name = match entry.name() {
+            b"A       TXT" => b"\xD6\xD0\xCE\xC4    TXT",
+            b"B       TXT" => b"\xCE\xC4\xBC\xFE    TXT",
+            _ => continue,
+        };
+        entry.0[..11].copy_from_slice(name);
+        volume.write_dir_entry(offset, &entry).unwrap();
+    }
+
+    let lock = vfs_lock();
+    let options = FatMountOptions::parse("cache_mode=writethrough,codepage=936").unwrap();
+    mount(Some(Box::new(FatMountData::new(device.clone(), options)))).unwrap();
+    (lock, device)
+}
+
+/// Mount an empty volume, the writes reach the device at once
+fn mount_empty(options: &str) -> (MutexGuard<'static, ()>, Arc<MemDevice>) {
+    let device = Arc::new(MemDevice::zeroed(16 * MIB));
+    format_volume(device.clone(), FormatOptions::new()).unwrap();
+    let lock = vfs_lock();
+    let options = format!("cache_mode=writethrough,{}", options);
+    let options = FatMountOptions::parse(&options).unwrap();
+    mount(Some(Box::new(FatMountData::new(device.clone(), options)))).unwrap();
+    (lock, device)
+}
+
+fn create(path: &str) {
+    vfs_open_file::<FakeFSC>(
+        path,
+        OpenFlags::O_RDWR | OpenFlags::O_CREAT,
+        FileMode::FMODE_WRITE,
+    )
+    .unwrap();
+}
+
+/// The 8.3 name on disk of the file with the long name `name` in the root
+fn short_entry(device: &Arc<MemDevice>, name: &str) -> [u8; 11] {
+    let volume = Volume::new(FatDevice::new(device.clone())).unwrap();
+    let items = volume.read_dir_items(0).unwrap();
+    let item = items.iter().find(|x| x.name == name);
+    *item
+        .unwrap_or_else(|| panic!("{} is not in /", name))
+        .entry
+        .name()
+}
+
+fn root_names() -> Vec<String> {
+    let root = vfs_open_file::<FakeFSC>("/", OpenFlags::O_RDWR, FileMode::FMODE_READ).unwrap();
+    let mut buf = vec![0u8; 4096];
+    let len = vfs_readdir(root, &mut buf).unwrap();
+    let mut names = parse_dirents(&buf[..len])
+        .into_iter()
+        .map(|x| x.0)
+        .filter(|x| x != "." && x != "..")
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+fn open_file(path: &str) -> Arc<File> {
+    vfs_open_file::<FakeFSC>(path, OpenFlags::O_RDWR, FileMode::FMODE_READ).unwrap()
+}
+
+/// A dentry of `name` in the root that has no inode yet
+fn new_dentry(name: &str) -> Arc<DirEntry> {
+    let root = open_file("/").f_dentry.clone();
+    let inode = root.access_inner().d_inode.clone();
+    let dentry = DirEntry::new(
+        DirFlags::empty(),
+        inode,
+        FAT_DENTRY_OPS,
+        Arc::downgrade(&root),
+        name,
+    );
+    Arc::new(dentry)
+}
+
+fn open(path: &str) -> bool {
+    vfs_open_file::<FakeFSC>(path, OpenFlags::O_RDWR, FileMode::FMODE_READ).is_ok()
+}
+
+#[test]
+fn short_names_in_cp437() {
+    let _lock = mount_oem_names("");
+    assert_eq!(root_names(), ["¢A.TXT", "σB.TXT"]);
+    assert!(open("/¢A.TXT"));
+    assert!(open("/σb.txt"));
+}
+
+#[test]
+fn short_names_in_cp850() {
+    let _lock = mount_oem_names("codepage=850");
+    assert_eq!(root_names(), ["ÕB.TXT", "øA.TXT"]);
+    // the case is folded for the lookup
+    assert!(open("/ØA.TXT"));
+    assert!(open("/õb.txt"));
+    assert!(!open("/¢A.TXT"));
+}
+
+#[test]
+fn code_pages_round_trip() {
+    for number in [
+        437, 737, 775, 850, 852, 855, 857, 860, 861, 862, 863, 865, 866, 869,
+    ] {
+        let codepage = CodePage::from_number(number).unwrap();
+        for byte in 0..=255u8 {
+            let c = codepage.decode(byte);
+            if c != char::REPLACEMENT_CHARACTER {
+                assert_eq!(codepage.encode(c), Some(byte), "{} {:#x}", number, byte);
+            }
+        }
+    }
+    // every pair of CP936 comes back from its character
+    let cp936 = CodePage::from_number(936).unwrap();
+    let mut bytes = Vec::new();
+    for lead in 0x81..=0xFEu8 {
+        assert!(cp936.is_lead_byte(lead));
+        for trail in (0x40..=0xFEu8).filter(|x| *x != 0x7F) {
+            let c = cp936.decode_pair(lead, trail);
+            if c != char::REPLACEMENT_CHARACTER {
+                bytes.clear();
+                assert!(cp936.encode_to(c, &mut bytes), "{:#x} {:#x}", lead, trail);
+                assert_eq!(bytes, [lead, trail]);
+            }
+        }
+    }
+    assert_eq!(cp936.decode_str(b"\xD6\xD0\xCE\xC4.TXT"), "中文.TXT");
+    assert!(!cp936.is_lead_byte(b'A'));
+    assert_eq!(CodePage::default().number(), 437);
+    assert_eq!(CodePage::from_number(866).unwrap().decode(0x80), 'А');
+    assert_eq!(CodePage::CP437.encode('€'), None);
+}
+
+#[test]
+fn new_short_names_in_cp437() {
+    let (_lock, device) = mount_empty("codepage=437");
+    create("/été.txt");
+    // Ø is not in CP437
+    create("/ø.txt");
+    vfs_mkdir::<FakeFSC>("/répertoire", FileMode::FMODE_WRITE).unwrap();
+    assert_eq!(&short_entry(&device, "été.txt"), b"\x90T\x90     TXT");
+    assert_eq!(&short_entry(&device, "ø.txt"), b"_~1     TXT");
+    assert_eq!(&short_entry(&device, "répertoire"), b"R\x90PERT~1   ");
+    // the long names still belong to their short entries
+    assert!(check(FatDevice::new(device.clone()), false)
+        .unwrap()
+        .is_clean());
+    assert!(open("/ÉTÉ.TXT"));
+    assert!(open("/répert~1"));
+}
+
+#[test]
+fn new_short_names_in_cp850() {
+    let (_lock, device) = mount_empty("codepage=850");
+    create("/ø.txt");
+    // Õ is 0xE5 in CP850, it is saved as 0x05
+    create("/õb.txt");
+    create("/façade.txt");
+    create("/FAÇADE.TXT.bak");
+    assert_eq!(&short_entry(&device, "ø.txt"), b"\x9D       TXT");
+    assert_eq!(&short_entry(&device, "õb.txt"), b"\x05B      TXT");
+    assert_eq!(&short_entry(&device, "façade.txt"), b"FA\x80ADE  TXT");
+    assert_eq!(&short_entry(&device, "FAÇADE.TXT.bak"), b"FA\x80ADE~1BAK");
+    assert!(check(FatDevice::new(device.clone()), false)
+        .unwrap()
+        .is_clean());
+    assert_eq!(
+        root_names(),
+        ["FAÇADE.TXT.bak", "façade.txt", "õb.txt", "ø.txt"]
+    );
+    assert!(open("/Ø.TXT"));
+    assert!(open("/ÕB.TXT"));
+}
+
+#[test]
+fn short_names_in_cp936() {
+    let (_lock, device) = mount_gbk_names();
+    assert_eq!(root_names(), ["中文.TXT", "文件.TXT"]);
+    assert!(open("/中文.txt"));
+    assert!(!open("/中.TXT"));
+
+    // fatfs finds the files without long names for the rename and the unlink
+    let root = open_file("/").f_dentry.access_inner().d_inode.clone();
+    let file = open_file("/中文.TXT");
+    (FAT_INODE_DIR_OPS.rename)(
+        root.clone(),
+        file.f_dentry.clone(),
+        root.clone(),
+        new_dentry("新名.txt"),
+    )
+    .unwrap();
+    let file = open_file("/文件.TXT");
+    (FAT_INODE_DIR_OPS.unlink)(root, file.f_dentry.clone()).unwrap();
+    assert_eq!(root_names(), ["新名.txt"]);
+    assert_eq!(
+        &short_entry(&device, "新名.txt"),
+        b"\xD0\xC2\xC3\xFB    TXT"
+    );
+    assert!(check(FatDevice::new(device.clone()), false)
+        .unwrap()
+        .is_clean());
+}
+
+#[test]
+fn new_short_names_in_cp936() {
+    let (_lock, device) = mount_empty("codepage=936");
+    create("/中文.txt");
+    create("/中文字符.txt");
+    // the cut for ~1 does not split 字
+    create("/a中文字符.txt");
+    assert_eq!(
+        &short_entry(&device, "中文.txt"),
+        b"\xD6\xD0\xCE\xC4    TXT"
+    );
+    assert_eq!(
+        &short_entry(&device, "中文字符.txt"),
+        b"\xD6\xD0\xCE\xC4\xD7\xD6\xB7\xFBTXT"
+    );
+    assert_eq!(
+        &short_entry(&device, "a中文字符.txt"),
+        b"A\xD6\xD0\xCE\xC4~1 TXT"
+    );
+    assert!(check(FatDevice::new(device.clone()), false)
+        .unwrap()
+        .is_clean());
+    assert!(open("/中文.TXT"));
+    assert!(open("/a中文~1.txt"));
+}
@@ -0,0 +1,193 @@
+#![allow(dead_code)]
+use fat32_vfs::fstype::FAT;
+use rvfs::dentry::Dirent64;
+use rvfs::info::VfsError;
+use rvfs::mount::{do_mount, MountFlags, VfsMount};
+use rvfs::superblock::{register_filesystem, DataOps, Device};
+use rvfs::{init_process_info, mount_rootfs, FakeFSC, StrResult};
+use spin::Mutex;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::ptr::null;
+use std::sync::{Arc, MutexGuard, Once};
+
+static INIT: Once = Once::new();
+static VFS_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// mount the rootfs and register the fat filesystem only once for all tests
+pub fn init() {
+    INIT.call_once(|| {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let mnt = mount_rootfs();
+        init_process_info(mnt);
+        register_filesystem(FAT).unwrap();
+    });
+}
+
+pub fn mount(data: Option<Box<dyn DataOps>>) -> StrResult<Arc<VfsMount>> {
+    mount_with_flags(data, MountFlags::empty())
+}
+
+pub fn mount_with_flags(
+    data: Option<Box<dyn DataOps>>,
+    flags: MountFlags,
+) -> StrResult<Arc<VfsMount>> {
+    init();
+    do_mount::<FakeFSC>("fat32", "/", "fat", flags, data)
+}
+
+/// Every mount is on `/`, the tests that use the paths of a mounted volume hold the
+/// lock so that the last mount is their own
+pub fn vfs_lock() -> MutexGuard<'static, ()> {
+    VFS_LOCK.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// The names, the offsets and the inode numbers of the dirents in `buf`
+pub fn parse_dirents(buf: &[u8]) -> Vec<(String, i64, u64)> {
+    let mut dirents = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let dirent = unsafe { std::ptr::read_unaligned(buf[pos..].as_ptr() as *const Dirent64) };
+        let name = &buf[pos + std::mem::offset_of!(Dirent64, name)..];
+        let name = CStr::from_bytes_until_nul(name).unwrap().to_str().unwrap();
+        dirents.push((name.to_string(), dirent.off, dirent.ino));
+        pos += dirent.len();
+    }
+    dirents
+}
+
+/// An image in memory
+#[derive(Debug)]
+pub struct MemDevice(Mutex<Vec<u8>>);
+
+impl MemDevice {
+    pub fn new(data: Vec<u8>) -> Self {
+        MemDevice(Mutex::new(data))
+    }
+    pub fn zeroed(size: usize) -> Self {
+        Self::new(vec![0u8; size])
+    }
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.lock().clone()
+    }
+}
+
+impl Device for MemDevice {
+    fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, VfsError> {
+        let data = self.0.lock();
+        let start = offset.min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write(&self, buf: &[u8], offset: usize) -> Result<usize, VfsError> {
+        let mut data = self.0.lock();
+        let start = offset.min(data.len());
+        let len = buf.len().min(data.len() - start);
+        data[start..start + len].copy_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn size(&self) -> usize {
+        self.0.lock().len()
+    }
+
+    fn flush(&self) {}
+}
+
+/// A sparse image file in the temp directory, it is removed when the device is dropped.
+/// The volumes that are too large for memory live here.
+#[derive(Debug)]
+pub struct FileDevice {
+    file: File,
+    path: PathBuf,
+}
+
+impl FileDevice {
+    pub fn sparse(name: &str, size: u64) -> Self {
+        let path = std::env::temp_dir().join(format!("{}-{}.img", name, std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(size).unwrap();
+        FileDevice { file, path }
+    }
+}
+
+impl Drop for FileDevice {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Device for FileDevice {
+    fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, VfsError> {
+        let size = self.size();
+        let len = buf.len().min(size.saturating_sub(offset));
+        self.file
+            .read_exact_at(&mut buf[..len], offset as u64)
+            .unwrap();
+        Ok(len)
+    }
+
+    fn write(&self, buf: &[u8], offset: usize) -> Result<usize, VfsError> {
+        let size = self.size();
+        let len = buf.len().min(size.saturating_sub(offset));
+        self.file.write_all_at(&buf[..len], offset as u64).unwrap();
+        Ok(len)
+    }
+
+    fn size(&self) -> usize {
+        self.file.metadata().unwrap().len() as usize
+    }
+
+    fn flush(&self) {}
+}
+
+#[derive(Debug)]
+pub struct MemData {
+    device: Option<Arc<dyn Device>>,
+}
+
+impl MemData {
+    pub fn new(device: Option<Arc<dyn Device>>) -> Self {
+        MemData { device }
+    }
+}
+
+impl DataOps for MemData {
+    fn device(&self, _: &str) -> Option<Arc<dyn Device>> {
+        self.device.clone()
+    }
+
+    fn data(&self) -> *const u8 {
+        null()
+    }
+}
+
+/// A boot sector of a small fat16 volume with 512 byte sectors and 4 sectors per cluster
+pub fn fat16_boot_sector(total_sectors: u16) -> [u8; 512] {
+    let mut buf = [0u8; 512];
+    buf[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    buf[3..11].copy_from_slice(b"MSWIN4.1");
+    buf[11..13].copy_from_slice(&512u16.to_le_bytes());
+    buf[13] = 4;
+    buf[14..16].copy_from_slice(&1u16.to_le_bytes());
+    buf[16] = 2;
+    buf[17..19].copy_from_slice(&512u16.to_le_bytes());
+    buf[19..21].copy_from_slice(&total_sectors.to_le_bytes());
+    buf[21] = 0xF8;
+    buf[22..24].copy_from_slice(&32u16.to_le_bytes());
+    buf[38] = 0x29;
+    buf[43..54].copy_from_slice(b"NO NAME    ");
+    buf[54..62].copy_from_slice(b"FAT16   ");
+    buf[510..512].copy_from_slice(&[0x55, 0xAA]);
+    buf
+}
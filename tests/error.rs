@@ -0,0 +1,67 @@
+use fat32_vfs::error::{DeviceError, Errno, FatError};
+
+#[test]
+fn errno_of_every_error() {
+    let table = [
+        (FatError::Fs(fatfs::Error::NotFound), Errno::ENOENT),
+        (FatError::Fs(fatfs::Error::AlreadyExists), Errno::EEXIST),
+        (
+            FatError::Fs(fatfs::Error::DirectoryIsNotEmpty),
+            Errno::ENOTEMPTY,
+        ),
+        (FatError::Fs(fatfs::Error::NotEnoughSpace), Errno::ENOSPC),
+        (
+            FatError::Fs(fatfs::Error::InvalidFileNameLength),
+            Errno::ENAMETOOLONG,
+        ),
+        (FatError::Fs(fatfs::Error::InvalidInput), Errno::EINVAL),
+        (
+            FatError::Fs(fatfs::Error::UnsupportedFileNameCharacter),
+            Errno::EINVAL,
+        ),
+        (FatError::Fs(fatfs::Error::CorruptedFileSystem), Errno::EIO),
+        (FatError::Fs(fatfs::Error::UnexpectedEof), Errno::EIO),
+        (FatError::Fs(fatfs::Error::WriteZero), Errno::EIO),
+        (FatError::from(DeviceError::Read(0)), Errno::EIO),
+        (FatError::from(DeviceError::Write(512)), Errno::EIO),
+        (FatError::from(DeviceError::Seek), Errno::EIO),
+        (FatError::from(DeviceError::UnexpectedEof), Errno::EIO),
+        (FatError::from(DeviceError::WriteZero), Errno::EIO),
+        (FatError::from(DeviceError::ReadOnly), Errno::EROFS),
+        (FatError::NotDir, Errno::ENOTDIR),
+        (FatError::IsDir, Errno::EISDIR),
+        (FatError::FileTooBig, Errno::EFBIG),
+        (FatError::InvalidArgument, Errno::EINVAL),
+        (FatError::ReadOnly, Errno::EROFS),
+        (FatError::NoData, Errno::ENXIO),
+    ];
+    for (err, errno) in table {
+        let name = format!("{err:?}");
+        assert_eq!(err.errno(), errno, "{name}");
+        // the vfs only sees the message, it must lead back to the same errno
+        let msg: &'static str = err.into();
+        assert_eq!(Errno::from_message(msg), Some(errno), "{name}");
+    }
+}
+
+#[test]
+fn errno_messages_are_distinct() {
+    let errnos = [
+        Errno::ENOENT,
+        Errno::EIO,
+        Errno::ENXIO,
+        Errno::EEXIST,
+        Errno::ENOTDIR,
+        Errno::EISDIR,
+        Errno::EINVAL,
+        Errno::EFBIG,
+        Errno::ENOSPC,
+        Errno::EROFS,
+        Errno::ENAMETOOLONG,
+        Errno::ENOTEMPTY,
+    ];
+    for errno in errnos {
+        assert_eq!(Errno::from_message(errno.as_str()), Some(errno));
+    }
+    assert_eq!(Errno::from_message("Not an errno"), None);
+}
@@ -0,0 +1,403 @@
+mod common;
+
+use common::{mount, vfs_lock, MemDevice};
+use fat32_vfs::disk::{DosDateTime, Volume};
+use fat32_vfs::error::Errno;
+use fat32_vfs::extent::ExtentMap;
+use fat32_vfs::file::{fat_seek_data, fat_seek_hole, FAT_FILE_FILE_OPS};
+use fat32_vfs::format::{format_volume, FormatOptions};
+use fat32_vfs::fstype::FatDevice;
+use fat32_vfs::inode::{fat_getattr, fat_set_times, fat_truncate_to, FAT_INODE_FILE_OPS};
+use fat32_vfs::options::{FatMountData, FatMountOptions};
+use fat32_vfs::time::{Clock, Timespec};
+use fat32_vfs::{FatAttr, FatInode};
+use fatfs::{FileSystem, FsOptions, Write};
+use rvfs::file::{
+    vfs_open_file, vfs_read_file, vfs_write_file, File, FileMode, OpenFlags, SeekFrom,
+};
+use rvfs::inode::InodeAttr;
+use rvfs::stat::StatTime;
+use rvfs::FakeFSC;
+use std::sync::{Arc, MutexGuard};
+
+const MIB: usize = 1024 * 1024;
+
+/// Mount a new FAT16 volume with 512 byte clusters
+fn mount_volume() -> (MutexGuard<'static, ()>, Arc<MemDevice>) {
+    let lock = vfs_lock();
+    let device = Arc::new(MemDevice::zeroed(16 * MIB));
+    let options = FormatOptions::new().bytes_per_cluster(512);
+    format_volume(device.clone(), options).unwrap();
+    let data = FatMountData::new(device.clone(), FatMountOptions::default());
+    mount(Some(Box::new(data))).unwrap();
+    (lock, device)
+}
+
+fn create(path: &str) -> Arc<File> {
+    vfs_open_file::<FakeFSC>(
+        path,
+        OpenFlags::O_RDWR | OpenFlags::O_CREAT,
+        FileMode::FMODE_WRITE | FileMode::FMODE_READ,
+    )
+    .unwrap()
+}
+
+fn file_size(file: &Arc<File>) -> usize {
+    file.f_dentry
+        .access_inner()
+        .d_inode
+        .access_inner()
+        .file_size
+}
+
+fn fat_attr(file: &Arc<File>) -> FatAttr {
+    let inode = file.f_dentry.access_inner().d_inode.clone();
+    let inner = inode.access_inner();
+    let data = inner.data.as_ref().unwrap().data() as *const FatInode;
+    unsafe { *(*data).attr.lock() }
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|x| (x % 251) as u8).collect()
+}
+
+#[test]
+fn read_at_any_offset() {
+    let (_lock, _device) = mount_volume();
+    let file = create("/data.bin");
+    let data = pattern(5000);
+    assert_eq!(vfs_write_file::<FakeFSC>(file.clone(), &data, 0), Ok(5000));
+    // the reads dont depend on each other, the offsets jump back and forth
+    for (offset, len) in [(4000, 600), (0, 100), (511, 2), (1024, 1536), (3, 4997)] {
+        let mut buf = vec![0u8; len];
+        let count = vfs_read_file::<FakeFSC>(file.clone(), &mut buf, offset as u64).unwrap();
+        let expect = &data[offset..(offset + len).min(data.len())];
+        assert_eq!(&buf[..count], expect);
+    }
+    let mut buf = [0u8; 16];
+    assert_eq!(
+        vfs_read_file::<FakeFSC>(file.clone(), &mut buf, 5000),
+        Ok(0)
+    );
+    assert_eq!(vfs_read_file::<FakeFSC>(file, &mut buf, 10000), Ok(0));
+}
+
+#[test]
+fn read_sees_every_write() {
+    let (_lock, _device) = mount_volume();
+    let file = create("/grow.bin");
+    let mut buf = [0u8; 8];
+    assert_eq!(vfs_read_file::<FakeFSC>(file.clone(), &mut buf, 0), Ok(0));
+    vfs_write_file::<FakeFSC>(file.clone(), b"abcd", 0).unwrap();
+    assert_eq!(vfs_read_file::<FakeFSC>(file.clone(), &mut buf, 0), Ok(4));
+    assert_eq!(&buf[..4], b"abcd");
+    // the second cluster is found from the chain
+    vfs_write_file::<FakeFSC>(file.clone(), &pattern(600), 4).unwrap();
+    assert_eq!(vfs_read_file::<FakeFSC>(file.clone(), &mut buf, 600), Ok(4));
+    assert_eq!(&buf[..4], &pattern(600)[596..]);
+    // another open file of the same path reads the same data
+    let other =
+        vfs_open_file::<FakeFSC>("/grow.bin", OpenFlags::O_RDWR, FileMode::FMODE_READ).unwrap();
+    assert_eq!(vfs_read_file::<FakeFSC>(other, &mut buf, 0), Ok(8));
+    assert_eq!(&buf[..4], b"abcd");
+    assert_eq!(&buf[4..], &pattern(4)[..]);
+}
+
+#[test]
+fn read_fragmented_file() {
+    let (_lock, _device) = mount_volume();
+    let a = create("/a.bin");
+    let b = create("/b.bin");
+    let data = pattern(4 * 512);
+    // the clusters of the two files take turns on disk
+    for i in 0..4 {
+        let chunk = &data[i * 512..(i + 1) * 512];
+        vfs_write_file::<FakeFSC>(a.clone(), chunk, (i * 512) as u64).unwrap();
+        vfs_write_file::<FakeFSC>(b.clone(), &[i as u8; 512], (i * 512) as u64).unwrap();
+    }
+    let mut buf = vec![0u8; 4 * 512];
+    assert_eq!(
+        vfs_read_file::<FakeFSC>(a.clone(), &mut buf, 0),
+        Ok(4 * 512)
+    );
+    assert_eq!(buf, data);
+    let mut buf = [0u8; 4];
+    assert_eq!(vfs_read_file::<FakeFSC>(b.clone(), &mut buf, 1022), Ok(4));
+    assert_eq!(buf, [1, 1, 2, 2]);
+    // an overwrite across the clusters of two runs
+    vfs_write_file::<FakeFSC>(a.clone(), &[9u8; 4], 510).unwrap();
+    assert_eq!(vfs_read_file::<FakeFSC>(a, &mut buf, 510), Ok(4));
+    assert_eq!(buf, [9u8; 4]);
+    assert_eq!(vfs_read_file::<FakeFSC>(b, &mut buf, 510), Ok(4));
+    assert_eq!(buf, [0, 0, 1, 1]);
+}
+
+#[test]
+fn extent_map_of_a_chain() {
+    let device = Arc::new(MemDevice::zeroed(16 * MIB));
+    let options = FormatOptions::new().bytes_per_cluster(512);
+    format_volume(device.clone(), options).unwrap();
+    let fs = FileSystem::new(FatDevice::new(device.clone()), FsOptions::new()).unwrap();
+    let root = fs.root_dir();
+    let mut a = root.create_file("A.BIN").unwrap();
+    let mut b = root.create_file("B.BIN").unwrap();
+    a.write_all(&[1u8; 1024]).unwrap();
+    a.flush().unwrap();
+    b.write_all(&[2u8; 512]).unwrap();
+    b.flush().unwrap();
+    a.write_all(&[1u8; 1536]).unwrap();
+    drop(a);
+    drop(b);
+    fs.unmount().unwrap();
+
+    let volume = Volume::new(FatDevice::new(device)).unwrap();
+    let item = volume.find(0, "A.BIN").unwrap().unwrap();
+    let first = item.entry.first_cluster();
+    let map = ExtentMap::build(&volume, first).unwrap();
+    assert_eq!(map.clusters(), 5);
+    assert_eq!(map.extents().len(), 2);
+    assert_eq!(map.find(0), Some((first, 2)));
+    assert_eq!(map.find(1), Some((first + 1, 1)));
+    assert_eq!(map.find(2), Some((first + 3, 3)));
+    assert_eq!(map.find(4), Some((first + 5, 1)));
+    assert_eq!(map.find(5), None);
+    assert_eq!(ExtentMap::build(&volume, 0).unwrap().clusters(), 0);
+}
+
+#[test]
+fn llseek() {
+    let (_lock, _device) = mount_volume();
+    let file = create("/seek.bin");
+    vfs_write_file::<FakeFSC>(file.clone(), &pattern(1000), 0).unwrap();
+    let llseek = FAT_FILE_FILE_OPS.llseek;
+    assert_eq!(llseek(file.clone(), SeekFrom::End(0)), Ok(1000));
+    assert_eq!(llseek(file.clone(), SeekFrom::End(-10)), Ok(990));
+    assert_eq!(llseek(file.clone(), SeekFrom::Current(5)), Ok(995));
+    assert_eq!(llseek(file.clone(), SeekFrom::Current(10)), Ok(1005));
+    assert_eq!(llseek(file.clone(), SeekFrom::Start(3)), Ok(3));
+    let einval = Err(Errno::EINVAL.as_str());
+    assert_eq!(llseek(file.clone(), SeekFrom::End(-1001)), einval);
+    assert_eq!(llseek(file.clone(), SeekFrom::Current(-4)), einval);
+    assert_eq!(llseek(file.clone(), SeekFrom::Start(u64::MAX)), einval);
+    // the failed seeks keep the position
+    assert_eq!(llseek(file.clone(), SeekFrom::Current(0)), Ok(3));
+
+    assert_eq!(fat_seek_data(file.clone(), 100), Ok(100));
+    assert_eq!(fat_seek_hole(file.clone(), 100), Ok(1000));
+    let enxio = Err(Errno::ENXIO.as_str());
+    assert_eq!(fat_seek_data(file.clone(), 1000), enxio);
+    assert_eq!(fat_seek_hole(file.clone(), 1000), enxio);
+    assert_eq!(fat_seek_data(file, -1), enxio);
+
+    // a directory has no data to seek in
+    let root = vfs_open_file::<FakeFSC>("/", OpenFlags::O_RDWR, FileMode::FMODE_READ).unwrap();
+    assert_eq!(fat_seek_data(root.clone(), 0), einval);
+    assert_eq!(fat_seek_hole(root, 0), einval);
+}
+
+#[test]
+fn write_after_the_end() {
+    let (_lock, _device) = mount_volume();
+    let file = create("/sparse.bin");
+    vfs_write_file::<FakeFSC>(file.clone(), b"head", 0).unwrap();
+    // the gap is larger than one chunk of zeros and crosses many clusters
+    let offset = 100_000;
+    vfs_write_file::<FakeFSC>(file.clone(), b"tail", offset).unwrap();
+    let size = offset as usize + 4;
+    assert_eq!(
+        (FAT_FILE_FILE_OPS.llseek)(file.clone(), SeekFrom::End(0)),
+        Ok(size as u64)
+    );
+    assert_eq!(
+        file.f_dentry
+            .access_inner()
+            .d_inode
+            .access_inner()
+            .file_size,
+        size
+    );
+    let mut buf = vec![1u8; size];
+    assert_eq!(vfs_read_file::<FakeFSC>(file, &mut buf, 0), Ok(size));
+    assert_eq!(&buf[..4], b"head");
+    assert!(buf[4..offset as usize].iter().all(|x| *x == 0));
+    assert_eq!(&buf[offset as usize..], b"tail");
+}
+
+#[test]
+fn write_updates_the_inode() {
+    let (_lock, _device) = mount_volume();
+    let file = create("/attr.bin");
+    assert_eq!(file_size(&file), 0);
+    assert_eq!(fat_attr(&file).blocks, 0);
+    vfs_write_file::<FakeFSC>(file.clone(), &pattern(1000), 0).unwrap();
+    assert_eq!(file_size(&file), 1000);
+    let attr = fat_attr(&file);
+    assert_eq!(attr.blocks, 2);
+    assert!(attr.dirty);
+    // fsync saves the times in the entry
+    (FAT_FILE_FILE_OPS.fsync)(file.clone(), false).unwrap();
+    assert!(!fat_attr(&file).dirty);
+    // an overwrite keeps the size
+    vfs_write_file::<FakeFSC>(file.clone(), b"abc", 10).unwrap();
+    assert_eq!(file_size(&file), 1000);
+    assert!(fat_attr(&file).dirty);
+    vfs_write_file::<FakeFSC>(file.clone(), b"abc", 1024).unwrap();
+    assert_eq!(file_size(&file), 1027);
+    assert_eq!(fat_attr(&file).blocks, 3);
+}
+
+#[test]
+fn truncate_grows_and_shrinks() {
+    let (_lock, _device) = mount_volume();
+    let file = create("/trunc.bin");
+    vfs_write_file::<FakeFSC>(file.clone(), b"abcd", 0).unwrap();
+    let inode = file.f_dentry.access_inner().d_inode.clone();
+    // the vfs sets the size before it calls truncate
+    inode.access_inner().file_size = 1 << 20;
+    (FAT_INODE_FILE_OPS.truncate)(inode.clone()).unwrap();
+    assert_eq!(file_size(&file), 1 << 20);
+    assert_eq!(fat_attr(&file).blocks, 2048);
+    let mut buf = vec![1u8; 1 << 20];
+    assert_eq!(
+        vfs_read_file::<FakeFSC>(file.clone(), &mut buf, 0),
+        Ok(1 << 20)
+    );
+    assert_eq!(&buf[..4], b"abcd");
+    assert!(buf[4..].iter().all(|x| *x == 0));
+
+    fat_truncate_to(&inode, 2).unwrap();
+    assert_eq!(file_size(&file), 2);
+    assert_eq!(vfs_read_file::<FakeFSC>(file.clone(), &mut buf, 0), Ok(2));
+    assert_eq!(&buf[..2], b"ab");
+
+    let err = fat_truncate_to(&inode, 1 << 32).unwrap_err();
+    assert_eq!(err.errno(), Errno::EFBIG);
+    assert_eq!(file_size(&file), 2);
+}
+
+#[test]
+fn set_and_get_times() {
+    let (_lock, device) = mount_volume();
+    let file = create("/times.txt");
+    let inode = file.f_dentry.access_inner().d_inode.clone();
+    // 2024-03-15 10:20:33.7 and 2021-06-01 12:00:01.5 UTC
+    let atime = Timespec::new(1_710_498_033, 700_000_000);
+    let mtime = Timespec::new(1_622_548_801, 500_000_000);
+    fat_set_times(&inode, Some(atime), Some(mtime)).unwrap();
+    let stat = fat_getattr(&inode);
+    // the day of the access and 2 seconds of the modification are kept
+    assert_eq!(stat.atime, Timespec::new(1_710_460_800, 0));
+    assert_eq!(stat.mtime, Timespec::new(1_622_548_800, 0));
+
+    // none keeps a time
+    let later = Timespec::new(1_622_548_900, 0);
+    fat_set_times(&inode, None, Some(later)).unwrap();
+    assert_eq!(fat_getattr(&inode).atime, stat.atime);
+    assert_eq!(fat_getattr(&inode).mtime, later);
+
+    // the times are in the entry after fsync
+    (FAT_FILE_FILE_OPS.fsync)(file.clone(), false).unwrap();
+    let volume = Volume::new(FatDevice::new(device)).unwrap();
+    let (_, entry) = volume
+        .read_dir(0)
+        .unwrap()
+        .into_iter()
+        .find(|(_, entry)| entry.name() == b"TIMES   TXT")
+        .unwrap();
+    assert_eq!(entry.modified().to_timespec(0), later);
+    assert_eq!(entry.accessed().to_timespec(0), stat.atime);
+    assert_eq!(entry.created(), fat_attr(&file).crtime);
+}
+
+#[test]
+fn set_attr_and_lookup_fill_the_inode_times() {
+    let (_lock, device) = mount_volume();
+    let file = create("/utimens.txt");
+    let dentry = file.f_dentry.clone();
+    let inode = dentry.access_inner().d_inode.clone();
+    // 2021-06-01 12:00:02 UTC
+    let mtime = StatTime {
+        tv_sec: 1_622_548_802,
+        tv_nsec: 0,
+    };
+    let attr = InodeAttr {
+        atime: None,
+        mtime: Some(mtime),
+    };
+    (FAT_INODE_FILE_OPS.set_attr)(dentry, attr).unwrap();
+    assert_eq!(inode.access_inner().mtime, mtime);
+    let stat = fat_getattr(&inode);
+    assert_eq!(inode.access_inner().btime.tv_sec, stat.btime.sec as u64);
+    (FAT_FILE_FILE_OPS.fsync)(file, false).unwrap();
+
+    // a lookup after the remount builds the inode from the entry
+    let data = FatMountData::new(device, FatMountOptions::default());
+    mount(Some(Box::new(data))).unwrap();
+    let file =
+        vfs_open_file::<FakeFSC>("/utimens.txt", OpenFlags::O_RDWR, FileMode::FMODE_READ).unwrap();
+    let inode = file.f_dentry.access_inner().d_inode.clone();
+    let inner = inode.access_inner();
+    assert_eq!(inner.mtime, mtime);
+    assert_eq!(inner.atime.tv_sec, stat.atime.sec as u64);
+    assert_eq!(inner.btime.tv_sec, stat.btime.sec as u64);
+}
+
+#[test]
+fn dos_time_conversion() {
+    // 2000-02-29 23:59:59.99 is 23:59:58 and 199 units of 10 ms
+    let (time, centis) = DosDateTime::from_timespec(Timespec::new(951_868_799, 990_000_000));
+    assert_eq!(time.date, (20 << 9) | (2 << 5) | 29);
+    assert_eq!(time.time, (23 << 11) | (59 << 5) | 29);
+    assert_eq!(centis, 199);
+    assert_eq!(
+        time.to_timespec(centis),
+        Timespec::new(951_868_799, 990_000_000)
+    );
+    // the times FAT can not store are moved to the first or the last one
+    let (first, centis) = DosDateTime::from_timespec(Timespec::new(0, 0));
+    assert_eq!((first.date, first.time, centis), ((1 << 5) | 1, 0, 0));
+    let (last, _) = DosDateTime::from_timespec(Timespec::new(i64::MAX, 0));
+    assert_eq!(last.to_timespec(0), Timespec::new(4_354_819_198, 0));
+}
+
+/// A clock that stands still at 2023-07-04 18:30:45.25 UTC, two hours before the local time
+#[derive(Debug)]
+struct FixedClock;
+
+impl Clock for FixedClock {
+    fn now(&self) -> Timespec {
+        Timespec::new(1_688_495_445, 250_000_000)
+    }
+    fn utc_offset(&self) -> i32 {
+        120
+    }
+}
+
+#[test]
+fn times_in_local_time() {
+    let _lock = vfs_lock();
+    let device = Arc::new(MemDevice::zeroed(16 * MIB));
+    format_volume(device.clone(), FormatOptions::new()).unwrap();
+    let options = FatMountOptions {
+        clock: Some(Arc::new(FixedClock)),
+        ..FatMountOptions::default()
+    };
+    let data = FatMountData::new(device.clone(), options);
+    mount(Some(Box::new(data))).unwrap();
+    let file = create("/local.txt");
+    let inode = file.f_dentry.access_inner().d_inode.clone();
+
+    // fatfs and the inode take the time from the clock, it is local on disk
+    let (created, centis) = fat_attr(&file).crtime;
+    assert_eq!(created.date, (43 << 9) | (7 << 5) | 4);
+    assert_eq!(created.time, (20 << 11) | (30 << 5) | 22);
+    assert_eq!(centis, 125);
+    let stat = fat_getattr(&inode);
+    assert_eq!(stat.btime, FixedClock.now());
+    assert_eq!(stat.mtime, Timespec::new(1_688_495_444, 0));
+
+    vfs_write_file::<FakeFSC>(file.clone(), b"data", 0).unwrap();
+    assert_eq!(fat_attr(&file).mtime, created);
+    fat_set_times(&inode, None, Some(Timespec::new(1_688_400_000, 0))).unwrap();
+    assert_eq!(fat_attr(&file).mtime.time, 18 << 11);
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use common::MemDevice;
+use fat32_vfs::boot_sector::BootSector;
+use fat32_vfs::format::{format_volume, FormatOptions};
+use fat32_vfs::fstype::FatDevice;
+use fatfs::{FatType, FileSystem, FsOptions, Read, Write};
+use std::sync::Arc;
+
+const MIB: usize = 1024 * 1024;
+
+fn format(size: usize, options: FormatOptions) -> Arc<MemDevice> {
+    let device = Arc::new(MemDevice::zeroed(size));
+    format_volume(device.clone(), options).unwrap();
+    device
+}
+
+/// Check the boot sector and use the volume with fatfs
+fn check_volume(device: Arc<MemDevice>, fat_type: FatType) {
+    let boot_sector = BootSector::read_from(FatDevice::new(device.clone())).unwrap();
+    boot_sector.validate(device.bytes().len() as u64).unwrap();
+    assert_eq!(boot_sector.fat_type(), fat_type);
+
+    let fs = FileSystem::new(FatDevice::new(device), FsOptions::new()).unwrap();
+    assert_eq!(fs.fat_type(), fat_type);
+    let root = fs.root_dir();
+    root.create_dir("dir").unwrap();
+    let mut file = root.create_file("dir/hello.txt").unwrap();
+    file.write_all(b"hello world").unwrap();
+    drop(file);
+    let mut file = root.open_file("dir/hello.txt").unwrap();
+    let mut buf = [0u8; 11];
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello world");
+    drop(file);
+    fs.unmount().unwrap();
+}
+
+#[test]
+fn format_fat12() {
+    let device = format(4 * MIB, FormatOptions::new());
+    check_volume(device, FatType::Fat12);
+}
+
+#[test]
+fn format_fat16() {
+    let device = format(32 * MIB, FormatOptions::new());
+    check_volume(device, FatType::Fat16);
+}
+
+#[test]
+fn format_fat32() {
+    let device = format(64 * MIB, FormatOptions::new().fat_type(FatType::Fat32));
+    check_volume(device, FatType::Fat32);
+}
+
+#[test]
+fn format_with_options() {
+    let options = FormatOptions::new()
+        .fat_type(FatType::Fat16)
+        .bytes_per_cluster(2048)
+        .volume_id(0xCAFE_BABE)
+        .fats(1)
+        .reserved_sectors(4)
+        .volume_label("test")
+        .unwrap()
+        .oem_name("RVFS")
+        .unwrap();
+    let device = format(32 * MIB, options);
+    let boot_sector = BootSector::read_from(FatDevice::new(device.clone())).unwrap();
+    assert_eq!(boot_sector.cluster_size(), 2048);
+    assert_eq!(boot_sector.volume_id, 0xCAFE_BABE);
+    assert_eq!(boot_sector.fats, 1);
+    assert_eq!(boot_sector.reserved_sectors, 4);
+    assert_eq!(&boot_sector.volume_label, b"TEST       ");
+    assert_eq!(&boot_sector.oem_name, b"RVFS    ");
+    check_volume(device, FatType::Fat16);
+}
+
+#[test]
+fn format_invalid_options() {
+    let device = Arc::new(MemDevice::zeroed(4 * MIB));
+    // too few clusters for fat32
+    let options = FormatOptions::new().fat_type(FatType::Fat32);
+    assert!(format_volume(device.clone(), options).is_err());
+    let options = FormatOptions::new().bytes_per_cluster(3000);
+    assert!(format_volume(device.clone(), options).is_err());
+    let options = FormatOptions::new().fats(0);
+    assert!(format_volume(device, options).is_err());
+    assert!(FormatOptions::new()
+        .volume_label("a too long label")
+        .is_err());
+}
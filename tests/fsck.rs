@@ -0,0 +1,188 @@
+mod common;
+
+use common::MemDevice;
+use fat32_vfs::disk::Volume;
+use fat32_vfs::format::{format_volume, FormatOptions};
+use fat32_vfs::fsck::{check, FsckProblem};
+use fat32_vfs::fstype::FatDevice;
+use fatfs::{FatType, FileSystem, FsOptions, Write};
+use std::sync::Arc;
+
+const MIB: usize = 1024 * 1024;
+
+/// A volume with 512 byte clusters, it has `/A.TXT` (3 clusters), `/DIR` and
+/// `/DIR/a long file name.txt`
+fn volume(fat_type: FatType) -> Arc<MemDevice> {
+    let size = match fat_type {
+        FatType::Fat12 => MIB,
+        FatType::Fat16 => 16 * MIB,
+        FatType::Fat32 => 64 * MIB,
+    };
+    let device = Arc::new(MemDevice::zeroed(size));
+    let options = FormatOptions::new()
+        .fat_type(fat_type)
+        .bytes_per_cluster(512);
+    format_volume(device.clone(), options).unwrap();
+    let fs = FileSystem::new(FatDevice::new(device.clone()), FsOptions::new()).unwrap();
+    let root = fs.root_dir();
+    let mut file = root.create_file("A.TXT").unwrap();
+    file.write_all(&[1u8; 1500]).unwrap();
+    drop(file);
+    root.create_dir("DIR").unwrap();
+    let mut file = root.create_file("DIR/a long file name.txt").unwrap();
+    file.write_all(b"hello").unwrap();
+    drop(file);
+    fs.unmount().unwrap();
+    device
+}
+
+fn raw(device: &Arc<MemDevice>) -> Volume {
+    Volume::new(FatDevice::new(device.clone())).unwrap()
+}
+
+/// Find the short entry of a name in the root directory
+fn root_entry(volume: &Volume, name: &[u8; 11]) -> (u64, fat32_vfs::disk::RawDirEntry) {
+    volume
+        .read_dir(0)
+        .unwrap()
+        .into_iter()
+        .find(|(_, entry)| entry.name() == name)
+        .unwrap()
+}
+
+fn check_and_repair(device: &Arc<MemDevice>) -> Vec<FsckProblem> {
+    let report = check(FatDevice::new(device.clone()), true).unwrap();
+    assert!(report.repaired);
+    assert!(check(FatDevice::new(device.clone()), false)
+        .unwrap()
+        .is_clean());
+    report.problems
+}
+
+#[test]
+fn fsck_clean_volume() {
+    for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+        let device = volume(fat_type);
+        let report = check(FatDevice::new(device), false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!(report.files, 2);
+        assert_eq!(report.dirs, 2);
+    }
+}
+
+#[test]
+fn fsck_fat_mismatch() {
+    let device = volume(FatType::Fat16);
+    let volume = raw(&device);
+    let offset = volume.fat_offset(1) + 100;
+    volume.write_at(offset, &[0xAB]).unwrap();
+    let problems = check_and_repair(&device);
+    assert_eq!(problems, vec![FsckProblem::FatMismatch { fat: 1 }]);
+}
+
+#[test]
+fn fsck_lost_chain() {
+    let device = volume(FatType::Fat16);
+    let volume = raw(&device);
+    let end = volume.end_of_chain();
+    volume.set_fat_entry(1000, 1001).unwrap();
+    volume.set_fat_entry(1001, end).unwrap();
+    let problems = check_and_repair(&device);
+    assert_eq!(
+        problems,
+        vec![FsckProblem::LostChain {
+            first_cluster: 1000,
+            clusters: 2
+        }]
+    );
+}
+
+#[test]
+fn fsck_size_mismatch() {
+    let device = volume(FatType::Fat16);
+    let volume = raw(&device);
+    let (offset, mut entry) = root_entry(&volume, b"A       TXT");
+    entry.set_size(100);
+    volume.write_dir_entry(offset, &entry).unwrap();
+    let problems = check_and_repair(&device);
+    assert_eq!(
+        problems,
+        vec![FsckProblem::SizeMismatch {
+            path: "/A.TXT".into(),
+            size: 100,
+            clusters: 3
+        }]
+    );
+    let (_, entry) = root_entry(&raw(&device), b"A       TXT");
+    assert_eq!(volume.chain(entry.first_cluster()).unwrap().len(), 1);
+}
+
+#[test]
+fn fsck_cross_link() {
+    let device = volume(FatType::Fat16);
+    let volume = raw(&device);
+    let (_, file) = root_entry(&volume, b"A       TXT");
+    let (_, dir) = root_entry(&volume, b"DIR        ");
+    let dir_chain = volume.chain(dir.first_cluster()).unwrap();
+    // the directory continues into the file
+    volume
+        .set_fat_entry(*dir_chain.last().unwrap(), file.first_cluster())
+        .unwrap();
+    let problems = check_and_repair(&device);
+    assert_eq!(
+        problems,
+        vec![FsckProblem::CrossLink {
+            path: "/DIR".into(),
+            cluster: file.first_cluster()
+        }]
+    );
+}
+
+#[test]
+fn fsck_bad_dot_entries() {
+    let device = volume(FatType::Fat32);
+    let volume = raw(&device);
+    let (_, dir) = root_entry(&volume, b"DIR        ");
+    let entries = volume.read_dir(dir.first_cluster()).unwrap();
+    let (offset, mut dot_dot) = entries[1];
+    dot_dot.set_first_cluster(5);
+    volume.write_dir_entry(offset, &dot_dot).unwrap();
+    let problems = check_and_repair(&device);
+    assert_eq!(
+        problems,
+        vec![FsckProblem::BadDotEntries {
+            path: "/DIR".into()
+        }]
+    );
+}
+
+#[test]
+fn fsck_orphan_lfn() {
+    let device = volume(FatType::Fat16);
+    let volume = raw(&device);
+    let (_, dir) = root_entry(&volume, b"DIR        ");
+    let entries = volume.read_dir(dir.first_cluster()).unwrap();
+    // break the checksum of the short name
+    let (offset, mut entry) = *entries
+        .iter()
+        .find(|(_, x)| !x.is_lfn() && !x.is_dot())
+        .unwrap();
+    entry.0[0] = b'X';
+    volume.write_dir_entry(offset, &entry).unwrap();
+    let problems = check_and_repair(&device);
+    assert!(matches!(problems[..], [FsckProblem::OrphanLfn { .. }]));
+}
+
+#[test]
+fn fsck_fs_info_free_count() {
+    let device = volume(FatType::Fat32);
+    let volume = raw(&device);
+    let bs = volume.boot_sector();
+    let offset = bs.bytes_from_sector(bs.fs_info_sector as u32) + 488;
+    volume.write_at(offset, &7u32.to_le_bytes()).unwrap();
+    let problems = check_and_repair(&device);
+    assert!(matches!(
+        problems[..],
+        [FsckProblem::FsInfoFreeCount { stored: 7, .. }]
+    ));
+}
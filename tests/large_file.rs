@@ -0,0 +1,124 @@
+mod common;
+
+use common::{mount, vfs_lock, FileDevice};
+use fat32_vfs::disk::{Volume, MAX_FILE_SIZE};
+use fat32_vfs::error::Errno;
+use fat32_vfs::file::FAT_FILE_FILE_OPS;
+use fat32_vfs::format::{format_volume, FormatOptions};
+use fat32_vfs::fstype::FatDevice;
+use fat32_vfs::inode::fat_truncate_to;
+use fat32_vfs::options::{FatMountData, FatMountOptions};
+use fatfs::{FatType, FileSystem, FsOptions};
+use rvfs::file::{vfs_open_file, vfs_read_file, vfs_write_file, FileMode, OpenFlags, SeekFrom};
+use rvfs::FakeFSC;
+use std::sync::Arc;
+
+const MIB: u64 = 1024 * 1024;
+const GIB: u64 = 1024 * MIB;
+const CLUSTER_SIZE: u64 = 32 * 1024;
+
+/// A FAT32 volume with `/BIG.BIN` of `size` bytes. The clusters of the file are linked
+/// in the FAT but never written, so the image stays sparse.
+fn big_volume(size: u64) -> Arc<FileDevice> {
+    let device = Arc::new(FileDevice::sparse("fat32-vfs-big", 4 * GIB + 512 * MIB));
+    let options = FormatOptions::new()
+        .fat_type(FatType::Fat32)
+        .bytes_per_cluster(CLUSTER_SIZE as u32);
+    format_volume(device.clone(), options).unwrap();
+    let fs = FileSystem::new(FatDevice::new(device.clone()), FsOptions::new()).unwrap();
+    fs.root_dir().create_file("BIG.BIN").unwrap();
+    fs.unmount().unwrap();
+
+    let volume = Volume::new(FatDevice::new(device.clone())).unwrap();
+    // the clusters after the root directory are free
+    let first = volume.root_cluster() + 1;
+    let last = first + size.div_ceil(CLUSTER_SIZE) as u32 - 1;
+    let fat = (first..=last)
+        .flat_map(|cluster| {
+            let next = if cluster == last {
+                volume.end_of_chain()
+            } else {
+                cluster + 1
+            };
+            next.to_le_bytes()
+        })
+        .collect::<Vec<u8>>();
+    for copy in 0..volume.boot_sector().fats {
+        let offset = volume.fat_offset(copy) + first as u64 * 4;
+        volume.write_at(offset, &fat).unwrap();
+    }
+    let (offset, mut entry) = volume
+        .read_dir(0)
+        .unwrap()
+        .into_iter()
+        .find(|(_, entry)| entry.name() == b"BIG     BIN")
+        .unwrap();
+    entry.set_first_cluster(first);
+    entry.set_size(size as u32);
+    volume.write_dir_entry(offset, &entry).unwrap();
+    device
+}
+
+#[test]
+fn offsets_around_4gib() {
+    let device = big_volume(MAX_FILE_SIZE - 16);
+    let _lock = vfs_lock();
+    let data = FatMountData::new(device.clone(), FatMountOptions::default());
+    mount(Some(Box::new(data))).unwrap();
+    let file =
+        vfs_open_file::<FakeFSC>("/BIG.BIN", OpenFlags::O_RDWR, FileMode::FMODE_RDWR).unwrap();
+    let llseek = FAT_FILE_FILE_OPS.llseek;
+    assert_eq!(
+        llseek(file.clone(), SeekFrom::End(0)),
+        Ok(MAX_FILE_SIZE - 16)
+    );
+
+    // the last bytes of the largest file
+    let res = vfs_write_file::<FakeFSC>(file.clone(), b"tail", MAX_FILE_SIZE - 4);
+    assert_eq!(res, Ok(4));
+    assert_eq!(llseek(file.clone(), SeekFrom::End(0)), Ok(MAX_FILE_SIZE));
+    let mut buf = [1u8; 8];
+    let res = vfs_read_file::<FakeFSC>(file.clone(), &mut buf, MAX_FILE_SIZE - 8);
+    assert_eq!(res, Ok(8));
+    assert_eq!(&buf, b"\0\0\0\0tail");
+
+    // a write over the limit is cut short, a write after it fails
+    let res = vfs_write_file::<FakeFSC>(file.clone(), b"abcd", MAX_FILE_SIZE - 2);
+    assert_eq!(res, Ok(2));
+    let efbig = Err(Errno::EFBIG.as_str());
+    let res = vfs_write_file::<FakeFSC>(file.clone(), b"abcd", MAX_FILE_SIZE);
+    assert_eq!(res, efbig);
+    // the offset is not cut to 32 bits
+    let res = vfs_write_file::<FakeFSC>(file.clone(), b"abcd", 4 * GIB + 8);
+    assert_eq!(res, efbig);
+    let res = vfs_read_file::<FakeFSC>(file.clone(), &mut buf, 8);
+    assert_eq!(res, Ok(8));
+    assert_eq!(buf, [0u8; 8]);
+
+    let einval = Err(Errno::EINVAL.as_str());
+    let res = vfs_write_file::<FakeFSC>(file.clone(), b"abcd", 1 << 63);
+    assert_eq!(res, einval);
+    assert_eq!(
+        vfs_read_file::<FakeFSC>(file.clone(), &mut buf, 1 << 63),
+        einval
+    );
+    assert_eq!(
+        vfs_read_file::<FakeFSC>(file.clone(), &mut buf, MAX_FILE_SIZE),
+        Ok(0)
+    );
+    assert_eq!(
+        llseek(file.clone(), SeekFrom::Start(MAX_FILE_SIZE)),
+        Ok(MAX_FILE_SIZE)
+    );
+    assert_eq!(
+        llseek(file.clone(), SeekFrom::Start(MAX_FILE_SIZE + 1)),
+        Err(Errno::EINVAL.as_str())
+    );
+    let res = llseek(file.clone(), SeekFrom::Current(1));
+    assert_eq!(res, Err(Errno::EINVAL.as_str()));
+
+    let inode = file.f_dentry.access_inner().d_inode.clone();
+    let err = fat_truncate_to(&inode, MAX_FILE_SIZE + 1).unwrap_err();
+    assert_eq!(err.errno(), Errno::EFBIG);
+    assert_eq!(inode.access_inner().file_size as u64, MAX_FILE_SIZE);
+}
@@ -0,0 +1,152 @@
+mod common;
+
+use common::{fat16_boot_sector, mount, vfs_lock, MemData, MemDevice};
+use fat32_vfs::boot_sector::BootSector;
+use fat32_vfs::error::{Errno, FatError};
+use fat32_vfs::format::{format_volume, FormatOptions};
+use fat32_vfs::options::{FatMountData, FatMountOptions};
+use rvfs::file::{vfs_mkdir, FileMode};
+use rvfs::superblock::{DataOps, Device};
+use rvfs::FakeFSC;
+use std::sync::Arc;
+
+/// The mount data of another filesystem, its data is not a `FatMountOptions`
+#[derive(Debug)]
+struct ForeignData {
+    device: Arc<dyn Device>,
+    data: [u8; 2],
+}
+
+impl DataOps for ForeignData {
+    fn device(&self, _: &str) -> Option<Arc<dyn Device>> {
+        Some(self.device.clone())
+    }
+
+    fn data(&self) -> *const u8 {
+        self.data.as_ptr()
+    }
+}
+
+fn mount_image(image: Vec<u8>) -> Result<(), &'static str> {
+    let device = Arc::new(MemDevice::new(image));
+    mount(Some(Box::new(MemData::new(Some(device))))).map(|_| ())
+}
+
+fn errno<T>(result: Result<T, &'static str>) -> Option<Errno> {
+    result.err().and_then(Errno::from_message)
+}
+
+#[test]
+fn mount_without_data() {
+    assert_eq!(errno(mount(None)), Some(Errno::EINVAL));
+}
+
+#[test]
+fn mount_without_device() {
+    assert_eq!(
+        errno(mount(Some(Box::new(MemData::new(None))))),
+        Some(Errno::EINVAL)
+    );
+}
+
+#[test]
+fn mount_empty_image() {
+    assert_eq!(errno(mount_image(Vec::new())), Some(Errno::EINVAL));
+}
+
+#[test]
+fn mount_bad_boot_signature() {
+    let mut image = vec![0u8; 16 * 1024 * 1024];
+    let mut boot_sector = fat16_boot_sector(32768);
+    boot_sector[510] = 0;
+    image[..512].copy_from_slice(&boot_sector);
+    assert_eq!(errno(mount_image(image)), Some(Errno::EINVAL));
+}
+
+#[test]
+fn mount_unsupported_sector_size() {
+    let mut image = vec![0u8; 16 * 1024 * 1024];
+    let mut boot_sector = fat16_boot_sector(32768);
+    boot_sector[11..13].copy_from_slice(&256u16.to_le_bytes());
+    image[..512].copy_from_slice(&boot_sector);
+    assert_eq!(errno(mount_image(image)), Some(Errno::EINVAL));
+}
+
+#[test]
+fn mount_truncated_image() {
+    // the boot sector says 16 MiB, but only 1 MiB is there
+    let mut image = vec![0u8; 1024 * 1024];
+    image[..512].copy_from_slice(&fat16_boot_sector(32768));
+    assert_eq!(errno(mount_image(image)), Some(Errno::EINVAL));
+}
+
+#[test]
+fn mount_overflowing_layout() {
+    // a fat32 style boot sector whose fats end past 4G sectors
+    let mut image = vec![0u8; 1024 * 1024];
+    let mut boot_sector = fat16_boot_sector(2048);
+    boot_sector[22..24].copy_from_slice(&0u16.to_le_bytes());
+    boot_sector[36..40].copy_from_slice(&u32::MAX.to_le_bytes());
+    image[..512].copy_from_slice(&boot_sector);
+    let parsed = BootSector::parse(&boot_sector).unwrap();
+    assert_eq!(
+        parsed.validate(image.len() as u64),
+        Err("Invalid volume layout")
+    );
+    assert_eq!(errno(mount_image(image)), Some(Errno::EINVAL));
+}
+
+#[test]
+fn cluster_offset_of_reserved_cluster() {
+    let boot_sector = BootSector::parse(&fat16_boot_sector(32768)).unwrap();
+    boot_sector.validate(16 * 1024 * 1024).unwrap();
+    assert_eq!(
+        boot_sector.cluster_offset(2),
+        Some(boot_sector.first_data_sector() as u64 * 512)
+    );
+    assert_eq!(boot_sector.cluster_offset(1), None);
+    assert_eq!(boot_sector.cluster_offset(0), None);
+}
+
+#[test]
+fn mount_with_foreign_data() {
+    let _lock = vfs_lock();
+    let device = Arc::new(MemDevice::zeroed(16 * 1024 * 1024));
+    format_volume(device.clone(), FormatOptions::new()).unwrap();
+    // it would be a read only mount if the bytes were taken as options
+    let data = ForeignData {
+        device: device.clone(),
+        data: *b"ro",
+    };
+    mount(Some(Box::new(data))).unwrap();
+    vfs_mkdir::<FakeFSC>("/dir", FileMode::FMODE_WRITE).unwrap();
+}
+
+#[test]
+fn mount_with_fat_data() {
+    let _lock = vfs_lock();
+    let device = Arc::new(MemDevice::zeroed(16 * 1024 * 1024));
+    format_volume(device.clone(), FormatOptions::new()).unwrap();
+    let options = FatMountOptions::parse("ro").unwrap();
+    mount(Some(Box::new(FatMountData::new(device, options)))).unwrap();
+    assert_eq!(
+        errno(vfs_mkdir::<FakeFSC>("/dir", FileMode::FMODE_WRITE)),
+        Some(Errno::EROFS)
+    );
+}
+
+#[test]
+fn parse_time_options() {
+    let options = FatMountOptions::parse("cache=16").unwrap();
+    assert_eq!(options.time_offset, None);
+    let options = FatMountOptions::parse("tz=UTC").unwrap();
+    assert_eq!(options.time_offset, Some(0));
+    let options = FatMountOptions::parse("time_offset=-330,ro").unwrap();
+    assert_eq!(options.time_offset, Some(-330));
+    for bad in ["tz=CET", "time_offset=1441", "time_offset=abc"] {
+        assert!(matches!(
+            FatMountOptions::parse(bad),
+            Err(FatError::InvalidArgument)
+        ));
+    }
+}
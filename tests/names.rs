@@ -0,0 +1,223 @@
+mod common;
+
+use common::{mount, parse_dirents, vfs_lock, MemDevice};
+use fat32_vfs::disk::Volume;
+use fat32_vfs::error::Errno;
+use fat32_vfs::file::FAT_DENTRY_OPS;
+use fat32_vfs::format::{format_volume, FormatOptions};
+use fat32_vfs::fsck::check;
+use fat32_vfs::fstype::FatDevice;
+use fat32_vfs::inode::FAT_INODE_DIR_OPS;
+use fat32_vfs::options::{FatMountData, FatMountOptions};
+use fatfs::{FileSystem, FsOptions, Write};
+use rvfs::dentry::{DirEntry, DirFlags};
+use rvfs::file::{
+    vfs_mkdir, vfs_open_file, vfs_read_file, vfs_readdir, vfs_write_file, File, FileMode, OpenFlags,
+};
+use rvfs::FakeFSC;
+use std::sync::{Arc, MutexGuard};
+
+const MIB: usize = 1024 * 1024;
+
+fn mount_volume() -> MutexGuard<'static, ()> {
+    let lock = vfs_lock();
+    let device = Arc::new(MemDevice::zeroed(16 * MIB));
+    format_volume(device.clone(), FormatOptions::new()).unwrap();
+    let data = FatMountData::new(device, FatMountOptions::default());
+    mount(Some(Box::new(data))).unwrap();
+    lock
+}
+
+fn open(path: &str, flags: OpenFlags) -> Result<Arc<File>, &'static str> {
+    vfs_open_file::<FakeFSC>(path, flags, FileMode::FMODE_RDWR)
+}
+
+fn ino(file: &Arc<File>) -> usize {
+    file.f_dentry.access_inner().d_inode.number
+}
+
+fn root_dentry() -> Arc<DirEntry> {
+    open("/", OpenFlags::O_RDWR).unwrap().f_dentry.clone()
+}
+
+/// A dentry of `name` in the root that has no inode yet
+fn new_dentry(name: &str) -> Arc<DirEntry> {
+    let root = root_dentry();
+    let inode = root.access_inner().d_inode.clone();
+    let dentry = DirEntry::new(
+        DirFlags::empty(),
+        inode,
+        FAT_DENTRY_OPS,
+        Arc::downgrade(&root),
+        name,
+    );
+    Arc::new(dentry)
+}
+
+fn root_names() -> Vec<String> {
+    let root = open("/", OpenFlags::O_RDWR).unwrap();
+    let mut buf = vec![0u8; 4096];
+    let len = vfs_readdir(root, &mut buf).unwrap();
+    let mut names = parse_dirents(&buf[..len])
+        .into_iter()
+        .map(|x| x.0)
+        .filter(|x| x != "." && x != "..")
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn dentry_ops_fold_case() {
+    let _lock = mount_volume();
+    let dentry = root_dentry();
+    let compare = |a: &str, b: &str| (FAT_DENTRY_OPS.d_compare)(dentry.clone(), a, b);
+    let hash = |name: &str| (FAT_DENTRY_OPS.d_hash)(dentry.clone(), name);
+    for (a, b) in [
+        ("README.TXT", "readme.txt"),
+        ("Ärger.doc", "äRGER.DOC"),
+        ("Σίσυφος", "ΣΊΣΥΦΟΣ"),
+        ("foo.", "FOO"),
+        ("..", ".."),
+        ("straße", "STRAßE"),
+        ("ŉ.txt", "ŉ.TXT"),
+    ] {
+        assert!(compare(a, b), "{} {}", a, b);
+        assert_eq!(hash(a), hash(b), "{} {}", a, b);
+    }
+    for (a, b) in [
+        ("a.txt", "b.txt"),
+        ("straße", "STRASSE"),
+        ("ŉ", "ʼN"),
+        (".", ".."),
+        ("foo", "foo.x"),
+    ] {
+        assert!(!compare(a, b), "{} {}", a, b);
+    }
+}
+
+#[test]
+fn lookup_ignores_case() {
+    let _lock = mount_volume();
+    let file = open("/Readme.Txt", OpenFlags::O_RDWR | OpenFlags::O_CREAT).unwrap();
+    for path in ["/README.TXT", "/readme.txt", "/readme.txt."] {
+        assert_eq!(ino(&open(path, OpenFlags::O_RDWR).unwrap()), ino(&file));
+    }
+    // the case of the name is kept on disk
+    assert_eq!(root_names(), ["Readme.Txt"]);
+
+    // a name with dots at the end is saved without them
+    open("/notes...", OpenFlags::O_RDWR | OpenFlags::O_CREAT).unwrap();
+    assert_eq!(root_names(), ["Readme.Txt", "notes"]);
+    assert!(open("/NOTES", OpenFlags::O_RDWR).is_ok());
+}
+
+#[test]
+fn lookup_keeps_long_upper_case() {
+    let _lock = mount_volume();
+    // the upper case of ß is SS and the one of ŉ is ʼN, they are not folded
+    let strasse = open("/straße.txt", OpenFlags::O_RDWR | OpenFlags::O_CREAT).unwrap();
+    let n = open("/ŉ.txt", OpenFlags::O_RDWR | OpenFlags::O_CREAT).unwrap();
+    let found = open("/STRAßE.TXT", OpenFlags::O_RDWR).unwrap();
+    assert_eq!(ino(&found), ino(&strasse));
+    assert_eq!(ino(&open("/ŉ.TXT", OpenFlags::O_RDWR).unwrap()), ino(&n));
+    assert!(open("/STRASSE.TXT", OpenFlags::O_RDWR).is_err());
+    assert!(open("/ʼN.txt", OpenFlags::O_RDWR).is_err());
+
+    // fatfs would open straße.txt for it, so the other name can not be created
+    let root = root_dentry().access_inner().d_inode.clone();
+    let create = (FAT_INODE_DIR_OPS.create)(root, new_dentry("STRASSE.TXT"), FileMode::FMODE_WRITE);
+    assert_eq!(create, Err(Errno::EEXIST.as_str()));
+    assert_eq!(root_names(), ["straße.txt", "ŉ.txt"]);
+}
+
+#[test]
+fn fatfs_handle_of_the_entry_itself() {
+    let device = Arc::new(MemDevice::zeroed(16 * MIB));
+    format_volume(device.clone(), FormatOptions::new()).unwrap();
+    let fs = FileSystem::new(FatDevice::new(device.clone()), FsOptions::new()).unwrap();
+    let mut file = fs.root_dir().create_file("straße.txt").unwrap();
+    file.write_all(b"first").unwrap();
+    let mut file = fs.root_dir().create_file("B.TXT").unwrap();
+    file.write_all(b"second").unwrap();
+    drop(file);
+    fs.unmount().unwrap();
+    // a short name that fatfs takes for the long name straße.txt before it
+    let volume = Volume::new(FatDevice::new(device.clone())).unwrap();
+    let item = volume.find(0, "B.TXT").unwrap().unwrap();
+    let mut entry = item.entry;
+    entry.0[..11].copy_from_slice(b"STRASSE TXT");
+    volume.write_dir_entry(item.offset, &entry).unwrap();
+
+    let _lock = vfs_lock();
+    let options = FatMountOptions::parse("cache_mode=writethrough").unwrap();
+    mount(Some(Box::new(FatMountData::new(device.clone(), options)))).unwrap();
+    assert_eq!(root_names(), ["STRASSE.TXT", "straße.txt"]);
+    // the append needs the fatfs file, it must be the one of STRASSE.TXT
+    let file = open("/STRASSE.TXT", OpenFlags::O_RDWR).unwrap();
+    assert_eq!(vfs_write_file::<FakeFSC>(file.clone(), b"!", 6), Ok(1));
+    let mut buf = [0u8; 8];
+    assert_eq!(vfs_read_file::<FakeFSC>(file, &mut buf, 0), Ok(7));
+    assert_eq!(&buf[..7], b"second!");
+    let other = open("/straße.txt", OpenFlags::O_RDWR).unwrap();
+    assert_eq!(vfs_read_file::<FakeFSC>(other, &mut buf, 0), Ok(5));
+    assert_eq!(&buf[..5], b"first");
+    assert!(check(FatDevice::new(device), false).unwrap().is_clean());
+}
+
+#[test]
+fn create_case_collision() {
+    let _lock = mount_volume();
+    let file = open("/foo.txt", OpenFlags::O_RDWR | OpenFlags::O_CREAT).unwrap();
+    open("/a long name.txt", OpenFlags::O_RDWR | OpenFlags::O_CREAT).unwrap();
+    // the vfs finds the file, O_CREAT opens it
+    let again = open("/FOO.TXT", OpenFlags::O_RDWR | OpenFlags::O_CREAT).unwrap();
+    assert_eq!(ino(&again), ino(&file));
+    assert!(vfs_mkdir::<FakeFSC>("/Foo.Txt", FileMode::FMODE_WRITE).is_err());
+
+    // the inode operations find the other case and the short name too
+    let root = root_dentry().access_inner().d_inode.clone();
+    let eexist = Err(Errno::EEXIST.as_str());
+    for name in ["FOO.TXT", "Foo.txt", "ALONGN~1.TXT"] {
+        let create =
+            (FAT_INODE_DIR_OPS.create)(root.clone(), new_dentry(name), FileMode::FMODE_WRITE);
+        assert_eq!(create, eexist, "{}", name);
+        let mkdir =
+            (FAT_INODE_DIR_OPS.mkdir)(root.clone(), new_dentry(name), FileMode::FMODE_WRITE);
+        assert_eq!(mkdir, eexist, "{}", name);
+    }
+    assert_eq!(root_names(), ["a long name.txt", "foo.txt"]);
+}
+
+#[test]
+fn rename_case_only() {
+    let _lock = mount_volume();
+    let file = open("/foo.txt", OpenFlags::O_RDWR | OpenFlags::O_CREAT).unwrap();
+    vfs_write_file::<FakeFSC>(file.clone(), b"hello", 0).unwrap();
+    let root = root_dentry().access_inner().d_inode.clone();
+    let rename = |old: &Arc<File>, name: &str| {
+        (FAT_INODE_DIR_OPS.rename)(
+            root.clone(),
+            old.f_dentry.clone(),
+            root.clone(),
+            new_dentry(name),
+        )
+    };
+
+    // the long name is rewritten, the file stays where it is
+    rename(&file, "Foo.TXT").unwrap();
+    assert_eq!(root_names(), ["Foo.TXT"]);
+    let renamed = open("/Foo.TXT", OpenFlags::O_RDWR).unwrap();
+    assert_eq!(ino(&renamed), ino(&file));
+    let mut buf = [0u8; 5];
+    assert_eq!(vfs_read_file::<FakeFSC>(renamed, &mut buf, 0), Ok(5));
+    assert_eq!(&buf, b"hello");
+    rename(&file, "Foo.TXT").unwrap();
+    assert_eq!(root_names(), ["Foo.TXT"]);
+
+    // a short name without a long name needs new entries
+    let bar = open("/BAR", OpenFlags::O_RDWR | OpenFlags::O_CREAT).unwrap();
+    rename(&bar, "bar").unwrap();
+    assert_eq!(root_names(), ["Foo.TXT", "bar"]);
+    assert!(open("/bar", OpenFlags::O_RDWR).is_ok());
+}
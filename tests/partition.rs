@@ -0,0 +1,204 @@
+mod common;
+
+use common::{mount, vfs_lock, MemDevice};
+use fat32_vfs::format::{format_volume, FormatOptions};
+use fat32_vfs::fstype::FatDevice;
+use fat32_vfs::options::{FatMountData, FatMountOptions};
+use fat32_vfs::partition::{read_partitions, Guid, Partition, PartitionKind};
+use rvfs::file::{vfs_mkdir, FileMode};
+use rvfs::FakeFSC;
+use std::sync::Arc;
+
+const MIB: usize = 1024 * 1024;
+const SECTOR: usize = 512;
+/// The partitions start at 1 MiB and are 4 MiB long
+const START_LBA: usize = 2048;
+const SECTORS: usize = 8192;
+const BASIC_DATA: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+const UNIQUE: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+
+/// A disk with a fat volume at `START_LBA`, the partition table is left to the test
+fn disk_with_volume() -> Vec<u8> {
+    let volume = Arc::new(MemDevice::zeroed(SECTORS * SECTOR));
+    format_volume(volume.clone(), FormatOptions::new()).unwrap();
+    let mut disk = vec![0u8; 8 * MIB];
+    disk[START_LBA * SECTOR..(START_LBA + SECTORS) * SECTOR].copy_from_slice(&volume.bytes());
+    disk
+}
+
+fn set_mbr_entry(disk: &mut [u8], index: usize, system_id: u8, lba: u32, sectors: u32) {
+    let entry = &mut disk[446 + index * 16..446 + (index + 1) * 16];
+    entry[4] = system_id;
+    entry[8..12].copy_from_slice(&lba.to_le_bytes());
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// A protective MBR, the GPT header in LBA 1 and the entries from LBA 2
+fn set_gpt(disk: &mut [u8], entries: u32, entry_size: u32) {
+    set_mbr_entry(disk, 0, 0xEE, 1, (disk.len() / SECTOR - 1) as u32);
+    let table_len = entries as usize * entry_size as usize;
+    let mut table = vec![0u8; table_len];
+    table[0..16].copy_from_slice(&Guid::parse(BASIC_DATA).unwrap().0);
+    table[16..32].copy_from_slice(&Guid::parse(UNIQUE).unwrap().0);
+    table[32..40].copy_from_slice(&(START_LBA as u64).to_le_bytes());
+    table[40..48].copy_from_slice(&((START_LBA + SECTORS - 1) as u64).to_le_bytes());
+    for (i, x) in "DATA".encode_utf16().enumerate() {
+        table[56 + i * 2..58 + i * 2].copy_from_slice(&x.to_le_bytes());
+    }
+    disk[2 * SECTOR..2 * SECTOR + table_len].copy_from_slice(&table);
+    let header = &mut disk[SECTOR..2 * SECTOR];
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&entries.to_le_bytes());
+    header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+    header[88..92].copy_from_slice(&crc32(&table).to_le_bytes());
+    update_header_crc(disk);
+}
+
+fn update_header_crc(disk: &mut [u8]) {
+    let header = &mut disk[SECTOR..2 * SECTOR];
+    header[16..20].fill(0);
+    let crc = crc32(&header[..92]);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+}
+
+fn partitions(disk: Vec<u8>) -> Result<Vec<Partition>, &'static str> {
+    let mut device = FatDevice::new(Arc::new(MemDevice::new(disk)));
+    read_partitions(&mut device).map_err(|err| err.into())
+}
+
+/// Mount the partition and create a directory in it, nothing before it is written
+fn mount_partition(disk: Vec<u8>, options: &str) {
+    let _lock = vfs_lock();
+    let device = Arc::new(MemDevice::new(disk.clone()));
+    // the writes reach the device at once
+    let options = format!("cache_mode=writethrough,{}", options);
+    let options = FatMountOptions::parse(&options).unwrap();
+    let data = FatMountData::new(device.clone(), options);
+    mount(Some(Box::new(data))).unwrap();
+    vfs_mkdir::<FakeFSC>("/dir", FileMode::FMODE_WRITE).unwrap();
+    let bytes = device.bytes();
+    assert!(bytes[..START_LBA * SECTOR] == disk[..START_LBA * SECTOR]);
+    assert!(bytes != disk);
+}
+
+#[test]
+fn mbr_partitions() {
+    let mut disk = disk_with_volume();
+    set_mbr_entry(&mut disk, 1, 0x06, START_LBA as u32, SECTORS as u32);
+    let parts = partitions(disk.clone()).unwrap();
+    assert_eq!(parts.len(), 1);
+    assert_eq!(parts[0].number, 2);
+    assert_eq!(parts[0].start, (START_LBA * SECTOR) as u64);
+    assert_eq!(parts[0].len, (SECTORS * SECTOR) as u64);
+    assert!(matches!(
+        parts[0].kind,
+        PartitionKind::Mbr {
+            system_id: 0x06,
+            bootable: false
+        }
+    ));
+    mount_partition(disk, "partition=2");
+}
+
+#[test]
+fn mbr_logical_partition() {
+    let mut disk = disk_with_volume();
+    // the extended partition starts 1 sector before the volume, its EBR is there
+    let ebr_lba = START_LBA - 1;
+    set_mbr_entry(&mut disk, 0, 0x05, ebr_lba as u32, SECTORS as u32 + 1);
+    let ebr = &mut disk[ebr_lba * SECTOR..(ebr_lba + 1) * SECTOR];
+    set_mbr_entry(ebr, 0, 0x0B, 1, SECTORS as u32);
+    let parts = partitions(disk.clone()).unwrap();
+    assert_eq!(parts.len(), 1);
+    assert_eq!(parts[0].number, 5);
+    assert_eq!(parts[0].start, (START_LBA * SECTOR) as u64);
+}
+
+#[test]
+fn gpt_partitions() {
+    let mut disk = disk_with_volume();
+    set_gpt(&mut disk, 128, 128);
+    let parts = partitions(disk.clone()).unwrap();
+    assert_eq!(parts.len(), 1);
+    assert_eq!(parts[0].number, 1);
+    assert_eq!(parts[0].start, (START_LBA * SECTOR) as u64);
+    assert_eq!(parts[0].len, (SECTORS * SECTOR) as u64);
+    match &parts[0].kind {
+        PartitionKind::Gpt {
+            type_guid,
+            unique_guid,
+            name,
+        } => {
+            assert_eq!(type_guid.to_string(), BASIC_DATA);
+            assert_eq!(unique_guid.to_string(), UNIQUE);
+            assert_eq!(name, "DATA");
+        }
+        kind => panic!("not a GPT partition: {kind:?}"),
+    }
+    mount_partition(disk.clone(), "partition_label=DATA");
+    mount_partition(disk, &format!("partition_guid={UNIQUE}"));
+}
+
+#[test]
+fn protective_mbr_without_gpt() {
+    let mut disk = disk_with_volume();
+    set_mbr_entry(&mut disk, 0, 0xEE, 1, 100);
+    assert!(partitions(disk).is_err());
+}
+
+#[test]
+fn corrupt_gpt_header() {
+    let mut disk = disk_with_volume();
+    set_gpt(&mut disk, 128, 128);
+    // a changed field without a new crc
+    disk[SECTOR + 80] = 4;
+    assert!(partitions(disk.clone()).is_err());
+    // the same with a good header crc, the entries no longer match their crc
+    update_header_crc(&mut disk);
+    assert!(partitions(disk.clone()).is_err());
+    // the entries far after the end of the disk
+    set_gpt(&mut disk, 128, 128);
+    disk[SECTOR + 72..SECTOR + 80].copy_from_slice(&u64::MAX.to_le_bytes());
+    update_header_crc(&mut disk);
+    assert!(partitions(disk).is_err());
+}
+
+#[test]
+fn gpt_entry_size() {
+    // the entry size is a power of two from 128 to the block size
+    for (entry_size, ok) in [
+        (64, false),
+        (128, true),
+        (192, false),
+        (256, true),
+        (1024, false),
+    ] {
+        let mut disk = disk_with_volume();
+        set_gpt(&mut disk, 4, entry_size);
+        assert_eq!(partitions(disk).is_ok(), ok, "entry size {entry_size}");
+    }
+    let mut disk = disk_with_volume();
+    set_gpt(&mut disk, 4, 128);
+    disk[SECTOR + 84..SECTOR + 88].copy_from_slice(&u32::MAX.to_le_bytes());
+    update_header_crc(&mut disk);
+    assert!(partitions(disk).is_err());
+}
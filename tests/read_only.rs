@@ -0,0 +1,84 @@
+mod common;
+
+use common::{mount, mount_with_flags, vfs_lock, MemDevice};
+use fat32_vfs::error::Errno;
+use fat32_vfs::file::{FAT_DIR_FILE_OPS, FAT_FILE_FILE_OPS};
+use fat32_vfs::format::{format_volume, FormatOptions};
+use fat32_vfs::inode::fat_truncate_to;
+use fat32_vfs::options::{FatMountData, FatMountOptions};
+use rvfs::file::{vfs_mkdir, vfs_open_file, vfs_read_file, vfs_write_file, FileMode, OpenFlags};
+use rvfs::mount::MountFlags;
+use rvfs::FakeFSC;
+use std::sync::Arc;
+
+const MIB: usize = 1024 * 1024;
+
+fn format(size: usize) -> Arc<MemDevice> {
+    let device = Arc::new(MemDevice::zeroed(size));
+    format_volume(device.clone(), FormatOptions::new()).unwrap();
+    device
+}
+
+#[test]
+fn read_only_mount_does_not_write() {
+    let device = format(32 * MIB);
+    let image = device.bytes();
+    let _lock = vfs_lock();
+    let data = FatMountData::new(device.clone(), FatMountOptions::default());
+    mount_with_flags(Some(Box::new(data)), MountFlags::MNT_RDONLY).unwrap();
+    assert!(vfs_mkdir::<FakeFSC>("/dir", FileMode::FMODE_WRITE).is_err());
+    let res = vfs_open_file::<FakeFSC>(
+        "/file",
+        OpenFlags::O_RDWR | OpenFlags::O_CREAT,
+        FileMode::FMODE_WRITE,
+    );
+    assert!(res.is_err());
+    assert!(device.bytes() == image);
+}
+
+#[test]
+fn read_only_option_keeps_files() {
+    let device = format(16 * MIB);
+    let _lock = vfs_lock();
+    let data = FatMountData::new(device.clone(), FatMountOptions::default());
+    mount(Some(Box::new(data))).unwrap();
+    let file = vfs_open_file::<FakeFSC>(
+        "/file",
+        OpenFlags::O_RDWR | OpenFlags::O_CREAT,
+        FileMode::FMODE_WRITE | FileMode::FMODE_READ,
+    )
+    .unwrap();
+    vfs_write_file::<FakeFSC>(file.clone(), b"hello", 0).unwrap();
+    // the cache writes back, the data must be on the device for the next mount
+    (FAT_FILE_FILE_OPS.fsync)(file, false).unwrap();
+
+    let image = device.bytes();
+    let options = FatMountOptions::parse("ro").unwrap();
+    mount(Some(Box::new(FatMountData::new(device.clone(), options)))).unwrap();
+    let file = vfs_open_file::<FakeFSC>("/file", OpenFlags::O_RDWR, FileMode::FMODE_READ).unwrap();
+    let mut buf = [0u8; 5];
+    assert_eq!(vfs_read_file::<FakeFSC>(file.clone(), &mut buf, 0), Ok(5));
+    assert_eq!(&buf, b"hello");
+    assert!(vfs_write_file::<FakeFSC>(file.clone(), b"world", 0).is_err());
+    let inode = file.f_dentry.access_inner().d_inode.clone();
+    let err = fat_truncate_to(&inode, 0).unwrap_err();
+    assert_eq!(err.errno(), Errno::EROFS);
+    assert!(device.bytes() == image);
+}
+
+#[test]
+fn fsync_of_dir_keeps_entries() {
+    let device = format(16 * MIB);
+    let _lock = vfs_lock();
+    let data = FatMountData::new(device.clone(), FatMountOptions::default());
+    mount(Some(Box::new(data))).unwrap();
+    vfs_mkdir::<FakeFSC>("/dir", FileMode::FMODE_WRITE).unwrap();
+    let root = vfs_open_file::<FakeFSC>("/", OpenFlags::O_RDWR, FileMode::FMODE_READ).unwrap();
+    // the new entry is in the cache until the directory is synced
+    (FAT_DIR_FILE_OPS.fsync)(root, false).unwrap();
+
+    let options = FatMountOptions::parse("ro").unwrap();
+    mount(Some(Box::new(FatMountData::new(device, options)))).unwrap();
+    let dir = vfs_open_file::<FakeFSC>("/dir", OpenFlags::O_RDWR, FileMode::FMODE_READ);
+    assert!(dir.is_ok());
+}
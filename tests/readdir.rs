@@ -0,0 +1,150 @@
+mod common;
+
+use common::{mount, parse_dirents, vfs_lock, MemDevice};
+use fat32_vfs::error::Errno;
+use fat32_vfs::format::{format_volume, FormatOptions};
+use fat32_vfs::options::{FatMountData, FatMountOptions};
+use rvfs::file::{
+    vfs_llseek, vfs_mkdir, vfs_open_file, vfs_readdir, File, FileMode, OpenFlags, SeekFrom,
+};
+use rvfs::FakeFSC;
+use std::sync::{Arc, MutexGuard};
+
+const MIB: usize = 1024 * 1024;
+
+fn mount_volume() -> MutexGuard<'static, ()> {
+    let lock = vfs_lock();
+    let device = Arc::new(MemDevice::zeroed(16 * MIB));
+    let options = FormatOptions::new().bytes_per_cluster(512);
+    format_volume(device.clone(), options).unwrap();
+    let data = FatMountData::new(device, FatMountOptions::default());
+    mount(Some(Box::new(data))).unwrap();
+    lock
+}
+
+fn create_files(names: impl Iterator<Item = String>) {
+    for name in names {
+        let path = format!("/{}", name);
+        vfs_open_file::<FakeFSC>(
+            &path,
+            OpenFlags::O_RDWR | OpenFlags::O_CREAT,
+            FileMode::FMODE_WRITE,
+        )
+        .unwrap();
+    }
+}
+
+fn open_dir(path: &str) -> Arc<File> {
+    vfs_open_file::<FakeFSC>(path, OpenFlags::O_RDWR, FileMode::FMODE_READ).unwrap()
+}
+
+fn ino_of(file: &Arc<File>) -> u64 {
+    file.f_dentry.access_inner().d_inode.number as u64
+}
+
+/// Read the rest of the directory with a buffer of `buf_len` bytes
+fn read_all(dir: &Arc<File>, buf_len: usize) -> (Vec<(String, i64, u64)>, usize) {
+    let mut dirents = Vec::new();
+    let mut total = 0;
+    loop {
+        let mut buf = vec![0u8; buf_len];
+        let len = vfs_readdir(dir.clone(), &mut buf).unwrap();
+        if len == 0 {
+            return (dirents, total);
+        }
+        total += len;
+        dirents.extend(parse_dirents(&buf[..len]));
+    }
+}
+
+#[test]
+fn readdir_in_small_buffers() {
+    let _lock = mount_volume();
+    let names = (0..40).map(|i| format!("file number {}.txt", i));
+    create_files(names.clone());
+    let dir = open_dir("/");
+    let size = vfs_readdir(dir.clone(), &mut []).unwrap();
+    let (dirents, total) = read_all(&dir, 100);
+    // the size of the query is the size of the dirents
+    assert_eq!(size, total);
+    let mut found = dirents.iter().map(|x| x.0.clone()).collect::<Vec<_>>();
+    let mut expect = names.chain([".".into(), "..".into()]).collect::<Vec<_>>();
+    found.sort();
+    expect.sort();
+    assert_eq!(found, expect);
+    assert!(dirents.windows(2).all(|x| x[0].1 < x[1].1));
+    // nothing is left after the end
+    assert_eq!(vfs_readdir(dir, &mut []), Ok(0));
+}
+
+#[test]
+fn readdir_cookies_stay_valid() {
+    let _lock = mount_volume();
+    create_files((0..10).map(|i| format!("OLD{}.TXT", i)));
+    let dir = open_dir("/");
+    let mut buf = vec![0u8; 100];
+    let len = vfs_readdir(dir.clone(), &mut buf).unwrap();
+    let first = parse_dirents(&buf[..len]);
+    assert!(first.len() > 2 && first.len() < 12);
+    // new files while the directory is read
+    create_files((0..5).map(|i| format!("NEW{}.TXT", i)));
+    let (rest, _) = read_all(&dir, 100);
+    let all = first.iter().chain(rest.iter()).cloned().collect::<Vec<_>>();
+    let mut names = all.iter().map(|x| x.0.clone()).collect::<Vec<_>>();
+    names.sort();
+    let mut expect = (0..5)
+        .map(|i| format!("NEW{}.TXT", i))
+        .chain((0..10).map(|i| format!("OLD{}.TXT", i)))
+        .chain([".".into(), "..".into()])
+        .collect::<Vec<_>>();
+    expect.sort();
+    assert_eq!(names, expect);
+
+    // seekdir to the offset of a dirent reads from the next one
+    for i in [0, 1, 2] {
+        let cookie = all[i].1 as u64;
+        assert_eq!(vfs_llseek(dir.clone(), SeekFrom::Start(cookie)), Ok(cookie));
+        assert_eq!(vfs_llseek(dir.clone(), SeekFrom::Current(0)), Ok(cookie));
+        let (again, _) = read_all(&dir, 1000);
+        assert_eq!(again, all[i + 1..]);
+    }
+    // rewinddir
+    assert_eq!(vfs_llseek(dir.clone(), SeekFrom::Start(0)), Ok(0));
+    assert_eq!(read_all(&dir, 1000).0, all);
+    // the cookies can not be added to
+    let einval = Err(Errno::EINVAL.as_str());
+    assert_eq!(vfs_llseek(dir.clone(), SeekFrom::Current(1)), einval);
+    assert_eq!(vfs_llseek(dir.clone(), SeekFrom::End(0)), einval);
+    assert_eq!(vfs_llseek(dir, SeekFrom::Start(u64::MAX)), einval);
+}
+
+#[test]
+fn readdir_dot_entries() {
+    let _lock = mount_volume();
+    vfs_mkdir::<FakeFSC>("/dir", FileMode::FMODE_WRITE).unwrap();
+    create_files(["dir/a.txt".to_string()].into_iter());
+    let root = open_dir("/");
+    let dir = open_dir("/dir");
+    let root_ino = ino_of(&root);
+    let dir_ino = ino_of(&dir);
+
+    // the root has no . and .. on disk, it is its own parent
+    let (dirents, _) = read_all(&root, 1000);
+    assert_eq!(dirents[0], (".".to_string(), 1, root_ino));
+    assert_eq!(dirents[1], ("..".to_string(), 2, root_ino));
+    assert_eq!(dirents.len(), 3);
+    assert_eq!(dirents[2].2, dir_ino);
+
+    // the ones on disk of a directory are not listed twice
+    let (dirents, _) = read_all(&dir, 1000);
+    let names = dirents.iter().map(|x| x.0.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, [".", "..", "a.txt"]);
+    assert_eq!(dirents[0].2, dir_ino);
+    assert_eq!(dirents[1].2, root_ino);
+
+    // .. resolves to the parent
+    let parent = open_dir("/dir/..");
+    assert_eq!(ino_of(&parent), root_ino);
+    let this = open_dir("/dir/.");
+    assert_eq!(ino_of(&this), dir_ino);
+}
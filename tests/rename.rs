@@ -0,0 +1,173 @@
+mod common;
+
+use common::{mount, parse_dirents, vfs_lock, MemDevice};
+use fat32_vfs::file::FAT_DENTRY_OPS;
+use fat32_vfs::format::{format_volume, FormatOptions};
+use fat32_vfs::inode::{fat_getattr, FAT_INODE_DIR_OPS};
+use fat32_vfs::options::{FatMountData, FatMountOptions};
+use rvfs::dentry::{DirEntry, DirFlags};
+use rvfs::file::{
+    vfs_mkdir, vfs_open_file, vfs_read_file, vfs_readdir, vfs_write_file, File, FileMode, OpenFlags,
+};
+use rvfs::FakeFSC;
+use std::sync::{Arc, MutexGuard};
+
+const MIB: usize = 1024 * 1024;
+
+fn mount_volume() -> MutexGuard<'static, ()> {
+    let lock = vfs_lock();
+    let device = Arc::new(MemDevice::zeroed(16 * MIB));
+    format_volume(device.clone(), FormatOptions::new()).unwrap();
+    remount(&device);
+    lock
+}
+
+/// Mount the device, every inode of the last mount then comes from a lookup. The writes
+/// reach the device at once, so the next mount sees them.
+fn remount(device: &Arc<MemDevice>) {
+    let options = FatMountOptions::parse("cache_mode=writethrough").unwrap();
+    let data = FatMountData::new(device.clone(), options);
+    mount(Some(Box::new(data))).unwrap();
+}
+
+fn read_all(path: &str) -> Vec<u8> {
+    let mut buf = vec![0u8; 64];
+    let len = vfs_read_file::<FakeFSC>(open(path), &mut buf, 0).unwrap();
+    buf.truncate(len);
+    buf
+}
+
+fn open(path: &str) -> Arc<File> {
+    vfs_open_file::<FakeFSC>(path, OpenFlags::O_RDWR, FileMode::FMODE_RDWR).unwrap()
+}
+
+fn create(path: &str) -> Arc<File> {
+    vfs_open_file::<FakeFSC>(
+        path,
+        OpenFlags::O_RDWR | OpenFlags::O_CREAT,
+        FileMode::FMODE_RDWR,
+    )
+    .unwrap()
+}
+
+/// The inode number that stat gives
+fn stat_ino(file: &Arc<File>) -> u64 {
+    fat_getattr(&file.f_dentry.access_inner().d_inode).ino as u64
+}
+
+/// The inode number of `name` in the dirents of the directory
+fn dirent_ino(dir: &str, name: &str) -> u64 {
+    let mut buf = vec![0u8; 4096];
+    let len = vfs_readdir(open(dir), &mut buf).unwrap();
+    parse_dirents(&buf[..len])
+        .into_iter()
+        .find(|x| x.0 == name)
+        .unwrap_or_else(|| panic!("{} is not in {}", name, dir))
+        .2
+}
+
+/// Rename `old` into the directory `new_dir` with the inode operation
+fn rename(old_dir: &str, old: &Arc<File>, new_dir: &str, name: &str) {
+    let old_dir = open(old_dir).f_dentry.access_inner().d_inode.clone();
+    let new_parent = open(new_dir).f_dentry.clone();
+    let new_dir = new_parent.access_inner().d_inode.clone();
+    let dentry = DirEntry::new(
+        DirFlags::empty(),
+        new_dir.clone(),
+        FAT_DENTRY_OPS,
+        Arc::downgrade(&new_parent),
+        name,
+    );
+    (FAT_INODE_DIR_OPS.rename)(old_dir, old.f_dentry.clone(), new_dir, Arc::new(dentry)).unwrap();
+}
+
+#[test]
+fn rename_across_dirs_keeps_ino() {
+    let _lock = mount_volume();
+    vfs_mkdir::<FakeFSC>("/a", FileMode::FMODE_WRITE).unwrap();
+    vfs_mkdir::<FakeFSC>("/b", FileMode::FMODE_WRITE).unwrap();
+    let file = create("/a/file");
+    let ino = stat_ino(&file);
+    assert_eq!(dirent_ino("/a", "file"), ino);
+
+    rename("/a", &file, "/b", "moved");
+    assert_eq!(stat_ino(&file), ino);
+    assert_eq!(dirent_ino("/b", "moved"), ino);
+    assert_eq!(stat_ino(&open("/b/moved")), ino);
+
+    // the new file takes the free slot of the old entry, not the number
+    let new = create("/a/new");
+    assert_ne!(stat_ino(&new), ino);
+    assert_eq!(dirent_ino("/a", "new"), stat_ino(&new));
+    assert_eq!(dirent_ino("/b", "moved"), ino);
+}
+
+#[test]
+fn rename_dir_updates_dot_dot() {
+    let _lock = mount_volume();
+    vfs_mkdir::<FakeFSC>("/a", FileMode::FMODE_WRITE).unwrap();
+    vfs_mkdir::<FakeFSC>("/b", FileMode::FMODE_WRITE).unwrap();
+    vfs_mkdir::<FakeFSC>("/a/sub", FileMode::FMODE_WRITE).unwrap();
+    let sub = open("/a/sub");
+    let ino = stat_ino(&sub);
+    assert_eq!(dirent_ino("/a/sub", ".."), stat_ino(&open("/a")));
+
+    rename("/a", &sub, "/b", "sub2");
+    assert_eq!(dirent_ino("/b", "sub2"), ino);
+    assert_eq!(dirent_ino("/b/sub2", "."), ino);
+    assert_eq!(dirent_ino("/b/sub2", ".."), stat_ino(&open("/b")));
+
+    // the moved directory moves again, its children see its number
+    rename("/b", &open("/b/sub2"), "/", "top");
+    assert_eq!(dirent_ino("/", "top"), ino);
+    create("/top/child");
+    let top = open("/top");
+    assert_eq!(stat_ino(&top), ino);
+    assert_eq!(dirent_ino("/top", "."), ino);
+    assert_eq!(dirent_ino("/top", ".."), stat_ino(&open("/")));
+}
+
+#[test]
+fn rename_case_keeps_ino() {
+    let _lock = mount_volume();
+    // a short name without a long name gets new entries for another case
+    let bar = create("/BAR");
+    let ino = stat_ino(&bar);
+    rename("/", &bar, "/", "bar");
+    assert_eq!(stat_ino(&bar), ino);
+    assert_eq!(dirent_ino("/", "bar"), ino);
+    assert_eq!(stat_ino(&open("/bar")), ino);
+
+    let baz = create("/BAZ");
+    assert_ne!(stat_ino(&baz), ino);
+    assert_eq!(dirent_ino("/", "BAZ"), stat_ino(&baz));
+}
+
+#[test]
+fn looked_up_inodes_open_fatfs_when_used() {
+    let _lock = vfs_lock();
+    let device = Arc::new(MemDevice::zeroed(16 * MIB));
+    format_volume(device.clone(), FormatOptions::new()).unwrap();
+    remount(&device);
+    vfs_mkdir::<FakeFSC>("/a", FileMode::FMODE_WRITE).unwrap();
+    vfs_mkdir::<FakeFSC>("/a/b", FileMode::FMODE_WRITE).unwrap();
+    vfs_write_file::<FakeFSC>(create("/a/b/file"), b"hello", 0).unwrap();
+
+    remount(&device);
+    let file = open("/a/b/file");
+    assert_eq!(read_all("/a/b/file"), b"hello");
+    // the file grows through fatfs, /a/b is opened through /a for the new file
+    vfs_write_file::<FakeFSC>(file.clone(), b" world", 5).unwrap();
+    create("/a/b/new");
+    assert_eq!(read_all("/a/b/file"), b"hello world");
+
+    // the fatfs file is opened again at the new entry after a rename
+    rename("/a/b", &file, "/a", "moved");
+    vfs_write_file::<FakeFSC>(file, b"!", 11).unwrap();
+    remount(&device);
+    assert_eq!(read_all("/a/moved"), b"hello world!");
+    assert!(
+        vfs_open_file::<FakeFSC>("/a/b/file", OpenFlags::O_RDWR, FileMode::FMODE_READ).is_err()
+    );
+    assert_eq!(read_all("/a/b/new"), b"");
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use common::{mount, vfs_lock, MemDevice};
+use fat32_vfs::format::{format_volume, FormatOptions};
+use fat32_vfs::options::{FatMountData, FatMountOptions};
+use fatfs::FatType;
+use rvfs::file::{vfs_open_file, vfs_write_file, File, FileMode, OpenFlags};
+use rvfs::superblock::StatFs;
+use rvfs::FakeFSC;
+use std::sync::Arc;
+
+const MIB: usize = 1024 * 1024;
+
+fn stat_fs(file: &Arc<File>) -> StatFs {
+    let inode = file.f_dentry.access_inner().d_inode.clone();
+    let sb_blk = inode.super_blk.upgrade().unwrap();
+    (sb_blk.super_block_ops.stat_fs)(sb_blk.clone()).unwrap()
+}
+
+/// The cluster size, the clusters and the FSInfo free count (FAT32 only) of an image,
+/// read from the BPB without the crate
+fn layout(image: &[u8]) -> (u64, u64, Option<u64>) {
+    let u16_at = |pos: usize| u16::from_le_bytes([image[pos], image[pos + 1]]) as u64;
+    let u32_at = |pos: usize| u32::from_le_bytes(image[pos..pos + 4].try_into().unwrap()) as u64;
+    let bytes_per_sector = u16_at(11);
+    let sectors_per_cluster = image[13] as u64;
+    let reserved = u16_at(14);
+    let fats = image[16] as u64;
+    let root_entries = u16_at(17);
+    let total_sectors = if u16_at(19) != 0 {
+        u16_at(19)
+    } else {
+        u32_at(32)
+    };
+    let sectors_per_fat = if u16_at(22) != 0 {
+        u16_at(22)
+    } else {
+        u32_at(36)
+    };
+    let root_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+    let data_sectors = total_sectors - reserved - fats * sectors_per_fat - root_sectors;
+    let clusters = data_sectors / sectors_per_cluster;
+    let free = (root_entries == 0).then(|| {
+        let fs_info = u16_at(48) * bytes_per_sector;
+        u32_at(fs_info as usize + 488)
+    });
+    (bytes_per_sector * sectors_per_cluster, clusters, free)
+}
+
+fn check_statfs(size: usize, options: FormatOptions) {
+    let _lock = vfs_lock();
+    let device = Arc::new(MemDevice::zeroed(size));
+    format_volume(device.clone(), options).unwrap();
+    let (cluster_size, clusters, fs_info_free) = layout(&device.bytes());
+    let data = FatMountData::new(device.clone(), FatMountOptions::default());
+    mount(Some(Box::new(data))).unwrap();
+
+    let file = vfs_open_file::<FakeFSC>(
+        "/data.bin",
+        OpenFlags::O_RDWR | OpenFlags::O_CREAT,
+        FileMode::FMODE_RDWR,
+    )
+    .unwrap();
+    let stat = stat_fs(&file);
+    assert_eq!(stat.block_size, cluster_size);
+    assert_eq!(stat.total_blocks, clusters);
+    // the root of FAT32 has one cluster, the one of FAT12/16 is outside the data area
+    let free = fs_info_free.unwrap_or(clusters);
+    if fs_info_free.is_some() {
+        assert_eq!(free, clusters - 1);
+    }
+    assert_eq!(stat.free_blocks, free);
+    // a file needs an entry and a cluster, there can not be more files than clusters
+    assert_eq!(stat.total_inodes, clusters);
+
+    let data = vec![7u8; 3 * cluster_size as usize];
+    vfs_write_file::<FakeFSC>(file.clone(), &data, 0).unwrap();
+    assert_eq!(stat_fs(&file).free_blocks, free - 3);
+}
+
+#[test]
+fn statfs_fat16() {
+    check_statfs(16 * MIB, FormatOptions::new().bytes_per_cluster(2048));
+}
+
+#[test]
+fn statfs_fat32() {
+    let options = FormatOptions::new()
+        .fat_type(FatType::Fat32)
+        .bytes_per_cluster(512);
+    check_statfs(64 * MIB, options);
+}